@@ -5,6 +5,8 @@ mod tests {
     use pico_args::Arguments;
     use rust_kvs::{ErrorCode, InstanceId, KvsValue, OpenNeedDefaults, OpenNeedKvs, SnapshotId};
     use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
 
     // Define a trait that mirrors the Kvs methods used in kvs_tool.rs
     trait KvsTrait {
@@ -31,8 +33,52 @@ mod tests {
         fn get_hash_filename(&self, id: SnapshotId) -> String;
     }
 
-    // Implement KvsTrait for Kvs (for real usage)
-    impl KvsTrait for Kvs {
+    // Raw store calls behind KvsTrait, made once without retrying. Implemented by Kvs and by
+    // the mock, so both run through the same forwarding layer below.
+    trait KvsBackend {
+        fn key_exists(&self, key: &str) -> Result<bool, ErrorCode>;
+        fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode>;
+        fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode>;
+        fn get_value<T: for<'a> TryFrom<&'a KvsValue> + Clone>(
+            &self,
+            key: &str,
+        ) -> Result<T, ErrorCode>
+        where
+            for<'a> <T as TryFrom<&'a KvsValue>>::Error: std::fmt::Debug;
+        fn set_value<S: Into<String>, J: Into<KvsValue>>(
+            &self,
+            key: S,
+            value: J,
+        ) -> Result<(), ErrorCode>;
+        fn remove_key(&self, key: &str) -> Result<(), ErrorCode>;
+        fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode>;
+        fn reset(&self) -> Result<(), ErrorCode>;
+        fn snapshot_count(&self) -> usize;
+        fn snapshot_restore(&self, id: SnapshotId) -> Result<(), ErrorCode>;
+        fn get_kvs_filename(&self, id: SnapshotId) -> String;
+        fn get_hash_filename(&self, id: SnapshotId) -> String;
+    }
+
+    // Attempts made by retry_on_lock before giving up on a busy backend
+    const LOCK_RETRY_ATTEMPTS: u32 = 5;
+
+    // Runs an operation that touches the KVS files, retrying while the backend reports
+    // ResourceBusy (another process holds the file lock). Any other error is returned at once.
+    fn retry_on_lock<T>(mut op: impl FnMut() -> Result<T, ErrorCode>) -> Result<T, ErrorCode> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(ErrorCode::ResourceBusy) if attempt < LOCK_RETRY_ATTEMPTS => {
+                    thread::sleep(Duration::from_millis(20 * attempt as u64));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Implement KvsBackend for Kvs (for real usage)
+    impl KvsBackend for Kvs {
         fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
             self.key_exists(key)
         }
@@ -81,10 +127,63 @@ mod tests {
         }
     }
 
-    // Create a mock for KvsTrait
+    // Forwarding layer from KvsTrait to any backend; calls that take the file lock are
+    // retried while the backend is busy
+    impl<B: KvsBackend> KvsTrait for B {
+        fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+            KvsBackend::key_exists(self, key)
+        }
+        fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+            KvsBackend::is_value_default(self, key)
+        }
+        fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+            KvsBackend::get_default_value(self, key)
+        }
+        fn get_value<T: for<'a> TryFrom<&'a KvsValue> + Clone>(
+            &self,
+            key: &str,
+        ) -> Result<T, ErrorCode>
+        where
+            for<'a> <T as TryFrom<&'a KvsValue>>::Error: std::fmt::Debug,
+        {
+            KvsBackend::get_value(self, key)
+        }
+        fn set_value<S: Into<String>, J: Into<KvsValue>>(
+            &self,
+            key: S,
+            value: J,
+        ) -> Result<(), ErrorCode> {
+            let key: String = key.into();
+            let value: KvsValue = value.into();
+            retry_on_lock(|| KvsBackend::set_value(self, key.clone(), value.clone()))
+        }
+        fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+            retry_on_lock(|| KvsBackend::remove_key(self, key))
+        }
+        fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+            KvsBackend::get_all_keys(self)
+        }
+        fn reset(&self) -> Result<(), ErrorCode> {
+            retry_on_lock(|| KvsBackend::reset(self))
+        }
+        fn snapshot_count(&self) -> usize {
+            KvsBackend::snapshot_count(self)
+        }
+        fn snapshot_restore(&self, id: SnapshotId) -> Result<(), ErrorCode> {
+            retry_on_lock(|| KvsBackend::snapshot_restore(self, id))
+        }
+        fn get_kvs_filename(&self, id: SnapshotId) -> String {
+            KvsBackend::get_kvs_filename(self, id)
+        }
+        fn get_hash_filename(&self, id: SnapshotId) -> String {
+            KvsBackend::get_hash_filename(self, id)
+        }
+    }
+
+    // Create a mock for KvsBackend; tests reach it through the KvsTrait forwarding layer
     mock! {
         KvsMock {}
-        impl KvsTrait for KvsMock {
+        impl KvsBackend for KvsMock {
             fn key_exists(&self, key: &str) -> Result<bool, ErrorCode>;
             fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode>;
             fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode>;
@@ -329,4 +428,40 @@ mod tests {
         let result = _createtestdata(&mock);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_forwarding_layer_retries_busy_backend() {
+        // Each method reports a held file lock on its first call and succeeds on the second
+        let mut backend = MockKvsMock::new();
+        let mut busy = true;
+        backend.expect_reset().times(2).returning(move || {
+            if std::mem::replace(&mut busy, false) {
+                return Err(ErrorCode::ResourceBusy);
+            }
+            Ok(())
+        });
+        let mut busy = true;
+        backend
+            .expect_set_value::<String, KvsValue>()
+            .times(2)
+            .returning(move |_, _| {
+                if std::mem::replace(&mut busy, false) {
+                    return Err(ErrorCode::ResourceBusy);
+                }
+                Ok(())
+            });
+        assert!(KvsTrait::reset(&backend).is_ok());
+        assert!(KvsTrait::set_value(&backend, "MyKey", KvsValue::Number(1.0)).is_ok());
+    }
+
+    #[test]
+    fn test_retry_on_lock_returns_other_errors_immediately() {
+        let mut calls = 0;
+        let result: Result<(), ErrorCode> = retry_on_lock(|| {
+            calls += 1;
+            Err(ErrorCode::KeyNotFound)
+        });
+        assert_eq!(result, Err(ErrorCode::KeyNotFound));
+        assert_eq!(calls, 1);
+    }
 }
\ No newline at end of file
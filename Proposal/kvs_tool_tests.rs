@@ -1,5 +1,7 @@
 use std::process::{Command, Output};
 use std::fs;
+use std::io;
+use std::path::Path;
 use std::thread;
 use std::time::Duration;
 
@@ -11,29 +13,58 @@ fn run_kvs_tool(args: Vec<&str>) -> Output {
         .expect("Failed to execute kvs_tool. Ensure `cargo build --bin kvs_tool` has been run.")
 }
 
-// Helper function to clean up test files with retries
-fn cleanup_test_files() {
-    let files = vec![
-        "kvs_0_0.json",
-        "kvs_0_0.hash",
-        "kvs_0_1.json",
-        "kvs_0_1.hash",
-        "kvs_0_2.json",
-        "kvs_0_2.hash",
-        "kvs_0_3.json",
-        "kvs_0_3.hash",
-        "kvs_0_default.json",
-    ];
-    for file in files {
-        for _ in 0..5 { // Increased retries
-            if fs::remove_file(file).is_ok() {
-                break;
+// Removes every kvs_0_*.json and kvs_0_*.hash file in `dir` and returns how many were deleted.
+// Only permission errors are retried, since on some platforms a file stays locked briefly
+// after the kvs_tool process that wrote it exits.
+fn remove_kvs_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !name.starts_with("kvs_0_") || !(name.ends_with(".json") || name.ends_with(".hash")) {
+            continue;
+        }
+        let mut attempts = 0;
+        loop {
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    removed += 1;
+                    break;
+                }
+                // Already gone, e.g. removed by a concurrently running test
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied && attempts < 5 => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => return Err(e),
             }
-            thread::sleep(Duration::from_millis(100)); // Increased delay
         }
     }
-    // Ensure cleanup is complete
-    thread::sleep(Duration::from_millis(500)); // Increased sleep
+    Ok(removed)
+}
+
+// Helper function to clean up the KVS files left in the working directory by earlier runs
+fn cleanup_test_files() {
+    remove_kvs_files(Path::new(".")).expect("Failed to clean up KVS test files");
+}
+
+#[test]
+fn test_remove_kvs_files_reports_count() {
+    let dir = std::env::temp_dir().join(format!("kvs_tool_cleanup_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for name in ["kvs_0_0.json", "kvs_0_0.hash", "config.json"] {
+        fs::write(dir.join(name), "{}").unwrap();
+    }
+
+    assert_eq!(remove_kvs_files(&dir).unwrap(), 2);
+    assert!(!dir.join("kvs_0_0.json").exists());
+    assert!(!dir.join("kvs_0_0.hash").exists());
+    assert!(dir.join("config.json").exists());
+    assert_eq!(remove_kvs_files(&dir).unwrap(), 0);
+    fs::remove_dir_all(&dir).unwrap();
 }
 
 #[test]
@@ -1,7 +1,13 @@
 use mockall::mock;
 use rust_kvs::{ErrorCode, KvsValue};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 use tinyjson::JsonValue;
 
 // Mocked Kvs trait without generics for dyn safety
@@ -10,14 +16,45 @@ trait KvsTrait {
     fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode>;
     fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode>;
     fn get_value_string(&self, key: &str) -> Result<String, ErrorCode>;
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode>;
     fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode>;
+    // Replaces the default a key falls back to; fails on backends that only read defaults
+    fn set_default_value(&self, _key: &str, _value: KvsValue) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
     fn remove_key(&self, key: &str) -> Result<(), ErrorCode>;
     fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode>;
+    // Keys that have a default, set or not; fails on backends that cannot list them
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
     fn reset(&self) -> Result<(), ErrorCode>;
     fn snapshot_count(&self) -> usize;
+    fn snapshot_create(&self) -> Result<u32, ErrorCode>;
     fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode>;
     fn get_kvs_filename(&self, id: u32) -> String;
     fn get_hash_filename(&self, id: u32) -> String;
+    // Moves the files of snapshot `id` to the given names
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode>;
+    // False for backends that keep no snapshots at all
+    fn supports_snapshots(&self) -> bool;
+    // False for backends that can only hand out values rendered as strings
+    fn supports_raw_reads(&self) -> bool {
+        true
+    }
+    // The store as a Sync object when it may be read from several threads at once
+    fn thread_safe(&self) -> Option<&(dyn KvsTrait + Sync)> {
+        None
+    }
+    // Writes out anything held back by a buffering layer; a no-op for write-through stores
+    fn flush(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
 }
 
 mock! {
@@ -27,447 +64,9180 @@ mock! {
         fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode>;
         fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode>;
         fn get_value_string(&self, key: &str) -> Result<String, ErrorCode>;
+        fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode>;
         fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode>;
+        fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode>;
         fn remove_key(&self, key: &str) -> Result<(), ErrorCode>;
         fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode>;
+        fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode>;
         fn reset(&self) -> Result<(), ErrorCode>;
         fn snapshot_count(&self) -> usize;
+        fn snapshot_create(&self) -> Result<u32, ErrorCode>;
         fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode>;
         fn get_kvs_filename(&self, id: u32) -> String;
         fn get_hash_filename(&self, id: u32) -> String;
+        fn supports_snapshots(&self) -> bool;
+        fn supports_raw_reads(&self) -> bool;
+        fn rename_snapshot_files(&self, id: u32, kvs_name: &str, hash_name: &str) -> Result<(), ErrorCode>;
     }
 }
 
-// Wrapper to invoke kvs_tool operations
-struct KvsToolWrapper {
-    kvs: Box<dyn KvsTrait>,
+// Decorator caching value reads until the next mutation passes through it
+struct CachingKvs {
+    inner: Box<dyn KvsTrait>,
+    strings: Mutex<HashMap<String, String>>,
+    raws: Mutex<HashMap<String, KvsValue>>,
 }
 
-impl KvsToolWrapper {
-    fn new(kvs: Box<dyn KvsTrait>) -> Self {
-        KvsToolWrapper { kvs }
+impl CachingKvs {
+    fn new(inner: Box<dyn KvsTrait>) -> Self {
+        CachingKvs {
+            inner,
+            strings: Mutex::new(HashMap::new()),
+            raws: Mutex::new(HashMap::new()),
+        }
     }
 
-    fn execute_operation(&self, args: Vec<&str>) -> Result<(), ErrorCode> {
-        let args: Vec<OsString> = args.into_iter().map(|s| s.into()).collect();
-        let mut pico_args = pico_args::Arguments::from_vec(args);
+    fn invalidate(&self) {
+        if let Ok(mut strings) = self.strings.lock() {
+            strings.clear();
+        }
+        if let Ok(mut raws) = self.raws.lock() {
+            raws.clear();
+        }
+    }
+}
 
-        if pico_args.contains(["-h", "--help"]) {
-            return Ok(());
+impl KvsTrait for CachingKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        let mut strings = self.strings.lock().map_err(|_| ErrorCode::UnmappedError)?;
+        if let Some(value) = strings.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.inner.get_value_string(key)?;
+        strings.insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        let mut raws = self.raws.lock().map_err(|_| ErrorCode::UnmappedError)?;
+        if let Some(value) = raws.get(key) {
+            return Ok(value.clone());
         }
+        let value = self.inner.get_value_raw(key)?;
+        raws.insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.invalidate();
+        self.inner.set_value(key, value)
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.invalidate();
+        self.inner.set_default_value(key, value)
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.invalidate();
+        self.inner.remove_key(key)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.invalidate();
+        self.inner.reset()
+    }
+    fn snapshot_count(&self) -> usize {
+        self.inner.snapshot_count()
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        self.inner.snapshot_create()
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        self.invalidate();
+        self.inner.snapshot_restore(id)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        self.inner.flush()
+    }
+}
 
-        let operation: Option<String> = pico_args
-            .opt_value_from_str(["-o", "--operation"])
-            .map_err(|_| ErrorCode::UnmappedError)?;
-        let op_mode = match operation.as_deref() {
-            Some("getkey") => "getkey",
-            Some("setkey") => "setkey",
-            Some("removekey") => "removekey",
-            Some("listkeys") => "listkeys",
-            Some("reset") => "reset",
-            Some("snapshotcount") => "snapshotcount",
-            Some("snapshotmaxcount") => "snapshotmaxcount",
-            Some("snapshotrestore") => "snapshotrestore",
-            Some("getkvsfilename") => "getkvsfilename",
-            Some("gethashfilename") => "gethashfilename",
-            Some("createtestdata") => "createtestdata",
-            _ => return Err(ErrorCode::UnmappedError),
-        };
+// Decorator for audit tooling: reads pass through, every mutation fails without reaching the store
+struct ReadOnlyKvs {
+    inner: Box<dyn KvsTrait>,
+}
 
-        match op_mode {
-            "getkey" => {
-                let key: String = pico_args
-                    .opt_value_from_str(["-k", "--key"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                if !self.kvs.key_exists(&key)? {
-                    return Err(ErrorCode::KeyNotFound);
-                }
-                let _ = self.kvs.is_value_default(&key)?;
-                let _ = self.kvs.get_default_value(&key)?;
-                let _ = self.kvs.get_value_string(&key)?;
-                Ok(())
-            }
-            "setkey" => {
-                let key: String = pico_args
-                    .opt_value_from_str(["-k", "--key"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                let value: String = pico_args
-                    .opt_value_from_str(["-p", "--payload"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                let kvs_value = if let Ok(json) = value.parse::<JsonValue>() {
-                    convert_json_to_kvs(&json)
-                } else {
-                    KvsValue::String(value)
-                };
-                self.kvs.set_value(&key, kvs_value)?;
-                Ok(())
-            }
-            "removekey" => {
-                let key: String = pico_args
-                    .opt_value_from_str(["-k", "--key"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                self.kvs.remove_key(&key)?;
-                Ok(())
-            }
-            "listkeys" => {
-                self.kvs.get_all_keys()?;
-                Ok(())
-            }
-            "reset" => {
-                self.kvs.reset()?;
-                Ok(())
-            }
-            "snapshotcount" => {
-                self.kvs.snapshot_count();
-                Ok(())
-            }
-            "snapshotmaxcount" => {
-                Ok(())
-            }
-            "snapshotrestore" => {
-                let snapshot_id: u32 = pico_args
-                    .opt_value_from_str(["-s", "--snapshotid"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                self.kvs.snapshot_restore(snapshot_id)?;
-                Ok(())
-            }
-            "getkvsfilename" => {
-                let snapshot_id: u32 = pico_args
-                    .opt_value_from_str(["-s", "--snapshotid"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                self.kvs.get_kvs_filename(snapshot_id);
-                Ok(())
-            }
-            "gethashfilename" => {
-                let snapshot_id: u32 = pico_args
-                    .opt_value_from_str(["-s", "--snapshotid"])
-                    .map_err(|_| ErrorCode::UnmappedError)?
-                    .ok_or(ErrorCode::UnmappedError)?;
-                self.kvs.get_hash_filename(snapshot_id);
-                Ok(())
+impl ReadOnlyKvs {
+    fn new(inner: Box<dyn KvsTrait>) -> Self {
+        ReadOnlyKvs { inner }
+    }
+}
+
+impl KvsTrait for ReadOnlyKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        self.inner.get_value_string(key)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.inner.get_value_raw(key)
+    }
+    fn set_value(&self, _key: &str, _value: KvsValue) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn set_default_value(&self, _key: &str, _value: KvsValue) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn remove_key(&self, _key: &str) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn snapshot_count(&self) -> usize {
+        self.inner.snapshot_count()
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn snapshot_restore(&self, _id: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        _id: u32,
+        _kvs_name: &str,
+        _hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::UnmappedError)
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        self.inner.flush()
+    }
+}
+
+// Decorator replaying a fault-injection scenario. Each scenario line names a method, the
+// 1-based index of its call that fails, and the ErrorCode it fails with, e.g.
+// `set_value 2 PhysicalStorageFailure`, or `panic` to make that call panic instead.
+// Blank lines and lines starting with '#' are ignored.
+struct ScenarioKvs {
+    inner: Box<dyn KvsTrait>,
+    failures: HashMap<(String, usize), String>,
+    calls: Mutex<HashMap<String, usize>>,
+}
+
+impl ScenarioKvs {
+    fn from_file(inner: Box<dyn KvsTrait>, path: &str) -> Result<Self, ToolError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ToolError::with_detail(ErrorCode::FileNotFound, format!("{}: {}", path, e))
+        })?;
+        let mut failures = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-            "createtestdata" => {
-                self.kvs.set_value("number", KvsValue::Number(123.0))?;
-                self.kvs.set_value("bool", KvsValue::Boolean(true))?;
-                self.kvs.set_value("string", KvsValue::String("First".to_string()))?;
-                self.kvs.set_value("null", KvsValue::Null)?;
-                self.kvs.set_value(
-                    "array",
-                    KvsValue::Array(vec![
-                        KvsValue::Number(456.0),
-                        KvsValue::Boolean(false),
-                        KvsValue::String("Second".to_string()),
-                    ]),
-                )?;
-                self.kvs.set_value(
-                    "object",
-                    KvsValue::Object(HashMap::from([
-                        ("sub-number".to_string(), KvsValue::Number(789.0)),
-                        ("sub-bool".to_string(), KvsValue::Boolean(true)),
-                        ("sub-string".to_string(), KvsValue::String("Third".to_string())),
-                        ("sub-null".to_string(), KvsValue::Null),
-                        (
-                            "sub-array".to_string(),
-                            KvsValue::Array(vec![
-                                KvsValue::Number(1246.0),
-                                KvsValue::Boolean(false),
-                                KvsValue::String("Fourth".to_string()),
-                            ]),
-                        ),
-                    ])),
-                )?;
-                Ok(())
+            let invalid = || {
+                ToolError::with_detail(
+                    ErrorCode::ValidationFailed,
+                    format!(
+                        "{}:{}: expected '<method> <call index> <ErrorCode>'",
+                        path,
+                        number + 1
+                    ),
+                )
+            };
+            let [method, index, code] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+            let index: usize = index.parse().map_err(|_| invalid())?;
+            if index == 0 || (code != "panic" && error_code_from_name(code).is_none()) {
+                return Err(invalid());
             }
-            _ => Err(ErrorCode::UnmappedError),
+            failures.insert((method.to_string(), index), code.to_string());
+        }
+        Ok(ScenarioKvs {
+            inner,
+            failures,
+            calls: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Counts a call to `method` and fails it if the scenario says so
+    fn check(&self, method: &str) -> Result<(), ErrorCode> {
+        let count = {
+            let mut calls = self.calls.lock().map_err(|_| ErrorCode::MutexLockFailed)?;
+            let count = calls.entry(method.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        // The lock is released above, so a panicking call leaves later calls usable
+        match self
+            .failures
+            .get(&(method.to_string(), count))
+            .map(String::as_str)
+        {
+            Some("panic") => panic!("scenario: {} call {} panics", method, count),
+            Some(code) => Err(error_code_from_name(code).unwrap_or(ErrorCode::UnmappedError)),
+            None => Ok(()),
         }
     }
 }
 
-// Convert tinyjson::JsonValue to KvsValue
-fn convert_json_to_kvs(json: &JsonValue) -> KvsValue {
-    match json {
-        JsonValue::Number(n) => KvsValue::Number(*n),
-        JsonValue::Boolean(b) => KvsValue::Boolean(*b),
-        JsonValue::String(s) => KvsValue::String(s.clone()),
-        JsonValue::Null => KvsValue::Null,
-        JsonValue::Array(arr) => KvsValue::Array(arr.iter().map(convert_json_to_kvs).collect()),
-        JsonValue::Object(obj) => KvsValue::Object(
-            obj.iter()
-                .map(|(k, v)| (k.clone(), convert_json_to_kvs(v)))
-                .collect(),
-        ),
+impl KvsTrait for ScenarioKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.check("key_exists")?;
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.check("is_value_default")?;
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.check("get_default_value")?;
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        self.check("get_value_string")?;
+        self.inner.get_value_string(key)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.check("get_value_raw")?;
+        self.inner.get_value_raw(key)
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.check("set_value")?;
+        self.inner.set_value(key, value)
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.check("set_default_value")?;
+        self.inner.set_default_value(key, value)
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.check("remove_key")?;
+        self.inner.remove_key(key)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.check("get_all_keys")?;
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.check("get_default_keys")?;
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.check("reset")?;
+        self.inner.reset()
+    }
+    fn snapshot_count(&self) -> usize {
+        self.inner.snapshot_count()
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        self.check("snapshot_create")?;
+        self.inner.snapshot_create()
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        self.check("snapshot_restore")?;
+        self.inner.snapshot_restore(id)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        self.check("rename_snapshot_files")?;
+        self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        self.inner.flush()
+    }
+}
+
+fn error_code_from_name(name: &str) -> Option<ErrorCode> {
+    Some(match name {
+        "UnmappedError" => ErrorCode::UnmappedError,
+        "FileNotFound" => ErrorCode::FileNotFound,
+        "KvsFileReadError" => ErrorCode::KvsFileReadError,
+        "KvsHashFileReadError" => ErrorCode::KvsHashFileReadError,
+        "JsonParserError" => ErrorCode::JsonParserError,
+        "JsonGeneratorError" => ErrorCode::JsonGeneratorError,
+        "PhysicalStorageFailure" => ErrorCode::PhysicalStorageFailure,
+        "IntegrityCorrupted" => ErrorCode::IntegrityCorrupted,
+        "ValidationFailed" => ErrorCode::ValidationFailed,
+        "EncryptionFailed" => ErrorCode::EncryptionFailed,
+        "ResourceBusy" => ErrorCode::ResourceBusy,
+        "OutOfStorageSpace" => ErrorCode::OutOfStorageSpace,
+        "QuotaExceeded" => ErrorCode::QuotaExceeded,
+        "AuthenticationFailed" => ErrorCode::AuthenticationFailed,
+        "KeyNotFound" => ErrorCode::KeyNotFound,
+        "SerializationFailed" => ErrorCode::SerializationFailed,
+        "InvalidSnapshotId" => ErrorCode::InvalidSnapshotId,
+        "ConversionFailed" => ErrorCode::ConversionFailed,
+        "MutexLockFailed" => ErrorCode::MutexLockFailed,
+        _ => return None,
+    })
+}
+
+// Test decorator that panics on any call to a method the test did not allow, including the
+// trait methods with a default body that MockKvsMock would otherwise answer without an
+// expectation. It catches calls slipping through an expectation set up without .times().
+// Configure the mock as usual, then list every method the test expects to reach the store:
+//
+//     let kvs = StrictMockKvs::new(mock).allow(&["key_exists", "get_value_raw"]);
+//     let wrapper = KvsToolWrapper::new(Box::new(kvs));
+struct StrictMockKvs {
+    inner: MockKvsMock,
+    allowed: HashSet<&'static str>,
+}
+
+impl StrictMockKvs {
+    fn new(inner: MockKvsMock) -> Self {
+        StrictMockKvs {
+            inner,
+            allowed: HashSet::new(),
+        }
+    }
+
+    fn allow(mut self, methods: &[&'static str]) -> Self {
+        self.allowed.extend(methods);
+        self
+    }
+
+    fn check(&self, method: &str) {
+        if !self.allowed.contains(method) {
+            panic!("Unexpected call to {} on StrictMockKvs", method);
+        }
+    }
+}
+
+impl KvsTrait for StrictMockKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.check("key_exists");
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.check("is_value_default");
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.check("get_default_value");
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        self.check("get_value_string");
+        self.inner.get_value_string(key)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.check("get_value_raw");
+        self.inner.get_value_raw(key)
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.check("set_value");
+        self.inner.set_value(key, value)
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.check("set_default_value");
+        self.inner.set_default_value(key, value)
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.check("remove_key");
+        self.inner.remove_key(key)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.check("get_all_keys");
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.check("get_default_keys");
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.check("reset");
+        self.inner.reset()
+    }
+    fn snapshot_count(&self) -> usize {
+        self.check("snapshot_count");
+        self.inner.snapshot_count()
     }
+    fn supports_snapshots(&self) -> bool {
+        self.check("supports_snapshots");
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.check("supports_raw_reads");
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        self.check("snapshot_create");
+        self.inner.snapshot_create()
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        self.check("snapshot_restore");
+        self.inner.snapshot_restore(id)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.check("get_kvs_filename");
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.check("get_hash_filename");
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        self.check("rename_snapshot_files");
+        self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        self.check("flush");
+        self.inner.flush()
+    }
+}
+
+// Decorator reporting every call that takes longer than a threshold to a sink
+struct TimingKvs {
+    inner: Box<dyn KvsTrait>,
+    threshold: Duration,
+    sink: Arc<dyn OutputSink>,
+}
+
+impl TimingKvs {
+    fn new(inner: Box<dyn KvsTrait>, threshold: Duration, sink: Arc<dyn OutputSink>) -> Self {
+        TimingKvs {
+            inner,
+            threshold,
+            sink,
+        }
+    }
+
+    fn timed<T>(&self, method: &str, call: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = call();
+        let elapsed = start.elapsed();
+        if elapsed > self.threshold {
+            self.sink.write_line(&format!(
+                "warning: slow {} took {:?} (threshold {:?})",
+                method, elapsed, self.threshold
+            ));
+        }
+        result
+    }
+}
+
+impl KvsTrait for TimingKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.timed("key_exists", || self.inner.key_exists(key))
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.timed("is_value_default", || self.inner.is_value_default(key))
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.timed("get_default_value", || self.inner.get_default_value(key))
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        self.timed("get_value_string", || self.inner.get_value_string(key))
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.timed("get_value_raw", || self.inner.get_value_raw(key))
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.timed("set_value", || self.inner.set_value(key, value))
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.timed("set_default_value", || {
+            self.inner.set_default_value(key, value)
+        })
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.timed("remove_key", || self.inner.remove_key(key))
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.timed("get_all_keys", || self.inner.get_all_keys())
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.timed("get_default_keys", || self.inner.get_default_keys())
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.timed("reset", || self.inner.reset())
+    }
+    fn snapshot_count(&self) -> usize {
+        self.timed("snapshot_count", || self.inner.snapshot_count())
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        self.timed("snapshot_create", || self.inner.snapshot_create())
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        self.timed("snapshot_restore", || self.inner.snapshot_restore(id))
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.timed("get_kvs_filename", || self.inner.get_kvs_filename(id))
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.timed("get_hash_filename", || self.inner.get_hash_filename(id))
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        self.timed("rename_snapshot_files", || {
+            self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+        })
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        self.timed("flush", || self.inner.flush())
+    }
+}
+
+// Decorator emitting a tracing event for every call, inside the span of the running
+// operation. Layer it under the wrapper to see the store traffic of each operation.
+#[cfg(feature = "tracing")]
+struct TracingKvs {
+    inner: Box<dyn KvsTrait>,
+}
+
+#[cfg(feature = "tracing")]
+impl TracingKvs {
+    fn new(inner: Box<dyn KvsTrait>) -> Self {
+        TracingKvs { inner }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl KvsTrait for TracingKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        tracing::debug!(method = "key_exists", key);
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        tracing::debug!(method = "is_value_default", key);
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        tracing::debug!(method = "get_default_value", key);
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        tracing::debug!(method = "get_value_string", key);
+        self.inner.get_value_string(key)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        tracing::debug!(method = "get_value_raw", key);
+        self.inner.get_value_raw(key)
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "set_value", key);
+        self.inner.set_value(key, value)
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "set_default_value", key);
+        self.inner.set_default_value(key, value)
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "remove_key", key);
+        self.inner.remove_key(key)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        tracing::debug!(method = "get_all_keys");
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        tracing::debug!(method = "get_default_keys");
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "reset");
+        self.inner.reset()
+    }
+    fn snapshot_count(&self) -> usize {
+        tracing::debug!(method = "snapshot_count");
+        self.inner.snapshot_count()
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        tracing::debug!(method = "snapshot_create");
+        self.inner.snapshot_create()
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "snapshot_restore", id);
+        self.inner.snapshot_restore(id)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "rename_snapshot_files", id);
+        self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+    }
+    fn flush(&self) -> Result<(), ErrorCode> {
+        tracing::debug!(method = "flush");
+        self.inner.flush()
+    }
+}
+
+// Decorator holding back set_value and remove_key until flush. Any other call flushes
+// first, so reads see the buffered writes. Writes still pending when it is dropped are
+// lost; call flush, or KvsToolWrapper::finish, before letting it go.
+struct BatchedKvs {
+    inner: Box<dyn KvsTrait>,
+    // Buffered writes in call order; None removes the key
+    pending: Mutex<Vec<(String, Option<KvsValue>)>>,
+}
+
+impl BatchedKvs {
+    fn new(inner: Box<dyn KvsTrait>) -> Self {
+        BatchedKvs {
+            inner,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn buffer(&self, key: &str, value: Option<KvsValue>) -> Result<(), ErrorCode> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| ErrorCode::MutexLockFailed)?;
+        pending.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+impl KvsTrait for BatchedKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.flush()?;
+        self.inner.key_exists(key)
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        self.flush()?;
+        self.inner.is_value_default(key)
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.inner.get_default_value(key)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        self.flush()?;
+        self.inner.get_value_string(key)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.flush()?;
+        self.inner.get_value_raw(key)
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.buffer(key, Some(value))
+    }
+    fn set_default_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.flush()?;
+        self.inner.set_default_value(key, value)
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.buffer(key, None)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.flush()?;
+        self.inner.get_all_keys()
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        self.inner.get_default_keys()
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.flush()?;
+        self.inner.reset()
+    }
+    fn snapshot_count(&self) -> usize {
+        self.inner.snapshot_count()
+    }
+    fn supports_snapshots(&self) -> bool {
+        self.inner.supports_snapshots()
+    }
+    fn supports_raw_reads(&self) -> bool {
+        self.inner.supports_raw_reads()
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        self.flush()?;
+        self.inner.snapshot_create()
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        self.flush()?;
+        self.inner.snapshot_restore(id)
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        self.inner.get_kvs_filename(id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        self.inner.get_hash_filename(id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        id: u32,
+        kvs_name: &str,
+        hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        self.flush()?;
+        self.inner.rename_snapshot_files(id, kvs_name, hash_name)
+    }
+    // Replays the buffered writes in order. On failure the rest stays buffered.
+    fn flush(&self) -> Result<(), ErrorCode> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| ErrorCode::MutexLockFailed)?;
+        while !pending.is_empty() {
+            let (key, value) = &pending[0];
+            match value {
+                Some(value) => self.inner.set_value(key, value.clone())?,
+                None => self.inner.remove_key(key)?,
+            }
+            pending.remove(0);
+        }
+        self.inner.flush()
+    }
+}
+
+// In-memory store for tests that need real state rather than expectations.
+// Unset keys fall back to their default like the file-backed Kvs.
+// Snapshot 1 is the most recent one, as with the file-backed Kvs.
+#[derive(Default)]
+struct MemKvs {
+    values: Mutex<HashMap<String, KvsValue>>,
+    defaults: HashMap<String, KvsValue>,
+    snapshots: Mutex<Vec<HashMap<String, KvsValue>>>,
+}
+
+impl MemKvs {
+    fn with_defaults(defaults: HashMap<String, KvsValue>) -> Self {
+        MemKvs {
+            defaults,
+            ..Default::default()
+        }
+    }
+
+    fn values(&self) -> Result<MutexGuard<'_, HashMap<String, KvsValue>>, ErrorCode> {
+        self.values.lock().map_err(|_| ErrorCode::MutexLockFailed)
+    }
+}
+
+impl KvsTrait for MemKvs {
+    fn key_exists(&self, key: &str) -> Result<bool, ErrorCode> {
+        Ok(self.values()?.contains_key(key) || self.defaults.contains_key(key))
+    }
+    fn is_value_default(&self, key: &str) -> Result<bool, ErrorCode> {
+        if self.values()?.contains_key(key) {
+            return Ok(false);
+        }
+        match self.defaults.contains_key(key) {
+            true => Ok(true),
+            false => Err(ErrorCode::KeyNotFound),
+        }
+    }
+    fn get_default_value(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        self.defaults
+            .get(key)
+            .cloned()
+            .ok_or(ErrorCode::KeyNotFound)
+    }
+    fn get_value_string(&self, key: &str) -> Result<String, ErrorCode> {
+        convert_kvs_to_json(&self.get_value_raw(key)?)
+            .stringify()
+            .map_err(|_| ErrorCode::JsonGeneratorError)
+    }
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        match self.values()?.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.get_default_value(key),
+        }
+    }
+    fn set_value(&self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        self.values()?.insert(key.to_string(), value);
+        Ok(())
+    }
+    fn remove_key(&self, key: &str) -> Result<(), ErrorCode> {
+        self.values()?
+            .remove(key)
+            .map(|_| ())
+            .ok_or(ErrorCode::KeyNotFound)
+    }
+    fn get_all_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        Ok(self.values()?.keys().cloned().collect())
+    }
+    fn get_default_keys(&self) -> Result<Vec<String>, ErrorCode> {
+        Ok(self.defaults.keys().cloned().collect())
+    }
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.values()?.clear();
+        Ok(())
+    }
+    fn snapshot_count(&self) -> usize {
+        self.snapshots.lock().map_or(0, |snapshots| snapshots.len())
+    }
+    fn supports_snapshots(&self) -> bool {
+        true
+    }
+    fn snapshot_create(&self) -> Result<u32, ErrorCode> {
+        let values = self.values()?.clone();
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .map_err(|_| ErrorCode::MutexLockFailed)?;
+        snapshots.insert(0, values);
+        Ok(1)
+    }
+    fn snapshot_restore(&self, id: u32) -> Result<(), ErrorCode> {
+        let snapshots = self
+            .snapshots
+            .lock()
+            .map_err(|_| ErrorCode::MutexLockFailed)?;
+        let snapshot = (id as usize)
+            .checked_sub(1)
+            .and_then(|index| snapshots.get(index))
+            .ok_or(ErrorCode::InvalidSnapshotId)?;
+        *self.values()? = snapshot.clone();
+        Ok(())
+    }
+    fn get_kvs_filename(&self, id: u32) -> String {
+        format!("mem_kvs_{}.json", id)
+    }
+    fn get_hash_filename(&self, id: u32) -> String {
+        format!("mem_kvs_{}.hash", id)
+    }
+    fn rename_snapshot_files(
+        &self,
+        _id: u32,
+        _kvs_name: &str,
+        _hash_name: &str,
+    ) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+    fn thread_safe(&self) -> Option<&(dyn KvsTrait + Sync)> {
+        Some(self)
+    }
+}
+
+// Result of a successful operation, for operations that report data back
+#[derive(Debug)]
+enum OperationOutcome {
+    Done,
+    AlreadyApplied,
+    Unchanged,
+    Namespaces(HashMap<String, usize>),
+    Plan(ChangePlan),
+    Applied(ChangePlan),
+    Rendered(String),
+    Imported(usize),
+    Sizes(Vec<(String, usize)>),
+    // Key names, possibly one page of them, and how many matched before paging
+    Keys {
+        names: Vec<String>,
+        total: usize,
+    },
+    Values(HashMap<String, KvsValue>),
+    Value(KvsValue),
+    // A value together with the kvs file (snapshot 0) holding it
+    Located {
+        value: KvsValue,
+        filename: String,
+    },
+    // Keys removed by reset (None under --no-count) and snapshots present when it ran
+    Reset {
+        keys_removed: Option<usize>,
+        snapshots: usize,
+    },
+    Count(usize),
+    // Number of generated values per KvsValue type name, in TYPE_NAMES order
+    TypeCounts(Vec<(&'static str, usize)>),
+    // Value count and estimated_size bytes per KvsValue type name in TYPE_NAMES order, plus the
+    // bytes of all values
+    SizeReport {
+        by_type: Vec<(&'static str, (usize, usize))>,
+        total_bytes: usize,
+    },
+    // Distribution of estimated_size over all values; all zero for an empty store
+    SizeStats {
+        count: usize,
+        min: usize,
+        max: usize,
+        mean: f64,
+        p50: usize,
+        p95: usize,
+    },
+    // Each key with its KvsValue type name, sorted by key
+    Types(Vec<(String, &'static str)>),
+    // Keys whose KvsValue type differs between two snapshots, with the type in each, sorted by key
+    TypeDrift(Vec<(String, &'static str, &'static str)>),
+    Strings(Vec<String>),
+    Renames(Vec<(String, String)>),
+    // Field-level diff (render_diff) of each differing key, sorted by key
+    Diffs(Vec<(String, String)>),
+    // Whether each named feature is available, in a fixed order
+    Capabilities(Vec<(&'static str, bool)>),
+    // Sets of keys belonging together, each sorted, ordered by their first key
+    Groups(Vec<Vec<String>>),
+    // Result of an operation run with --backup, with the snapshot taken before it
+    BackedUp {
+        snapshot_id: u32,
+        outcome: Box<OperationOutcome>,
+    },
+}
+
+impl OperationOutcome {
+    // Unpaged key list
+    fn keys(names: Vec<String>) -> Self {
+        let total = names.len();
+        OperationOutcome::Keys { names, total }
+    }
+}
+
+// Keys that would change to make the store match a desired state
+#[derive(Debug, Default)]
+struct ChangePlan {
+    create: Vec<String>,
+    update: Vec<String>,
+    delete: Vec<String>,
+    // Field-level diff (render_diff) of each key in `update`
+    diffs: HashMap<String, String>,
+}
+
+// Error of a failed operation: the error code plus optional detail for the user
+#[derive(Debug)]
+struct ToolError {
+    code: ToolErrorCode,
+    detail: Option<String>,
+}
+
+impl ToolError {
+    fn with_detail(code: impl Into<ToolErrorCode>, detail: impl Into<String>) -> Self {
+        ToolError {
+            code: code.into(),
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+impl From<ErrorCode> for ToolError {
+    fn from(code: ErrorCode) -> Self {
+        ToolError {
+            code: code.into(),
+            detail: None,
+        }
+    }
+}
+
+// A KVS ErrorCode, or a failure of the tool's own that no store call produced and that
+// ErrorCode has no variant for. Printed as the bare variant name either way.
+#[derive(PartialEq)]
+enum ToolErrorCode {
+    Kvs(ErrorCode),
+    // The operation is excluded by the deployment's allow or deny list
+    OperationNotPermitted,
+    // Two options were given that cannot be combined
+    ConflictingOptions,
+}
+
+impl From<ErrorCode> for ToolErrorCode {
+    fn from(code: ErrorCode) -> Self {
+        ToolErrorCode::Kvs(code)
+    }
+}
+
+impl PartialEq<ErrorCode> for ToolErrorCode {
+    fn eq(&self, other: &ErrorCode) -> bool {
+        matches!(self, ToolErrorCode::Kvs(code) if code == other)
+    }
+}
+
+impl std::fmt::Debug for ToolErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolErrorCode::Kvs(code) => code.fmt(f),
+            ToolErrorCode::OperationNotPermitted => f.write_str("OperationNotPermitted"),
+            ToolErrorCode::ConflictingOptions => f.write_str("ConflictingOptions"),
+        }
+    }
+}
+
+// Destination for text the tool reports besides operation outcomes
+trait OutputSink: Send + Sync {
+    fn write_line(&self, line: &str);
+    // Binary output such as decoded base64; sinks holding text get it as a lossy line
+    fn write_bytes(&self, bytes: &[u8]) {
+        self.write_line(&String::from_utf8_lossy(bytes));
+    }
+}
+
+struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write_line(&self, line: &str) {
+        println!("{}", line);
+    }
+    fn write_bytes(&self, bytes: &[u8]) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+    }
+}
+
+// Collects lines in memory, for tests
+impl OutputSink for Mutex<Vec<String>> {
+    fn write_line(&self, line: &str) {
+        if let Ok(mut lines) = self.lock() {
+            lines.push(line.to_string());
+        }
+    }
+}
+
+// Source of waiting for polling operations, replaceable so tests need not sleep
+trait Clock: Send + Sync {
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+// Any callback can stand in for the clock, e.g. to change the store between polls
+impl<F: Fn(Duration) + Send + Sync> Clock for F {
+    fn sleep(&self, duration: Duration) {
+        self(duration);
+    }
+}
+
+// Decision trace of one operation for --explain; steps reach the sink only when enabled
+struct Trace<'a> {
+    sink: Option<&'a dyn OutputSink>,
+}
+
+impl Trace<'_> {
+    fn step(&self, step: &str) {
+        if let Some(sink) = self.sink {
+            sink.write_line(&format!("explain: {}", step));
+        }
+    }
+}
+
+// Longest key accepted for writes. Storage limits are measured in UTF-8 bytes, never in
+// chars, so a key of multibyte characters reaches the limit with fewer characters.
+const MAX_KEY_BYTES: usize = 255;
+
+fn check_key_length(key: &str) -> Result<(), ToolError> {
+    if key.len() > MAX_KEY_BYTES {
+        return Err(ToolError::with_detail(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Key is {} bytes ({} characters), limit is {} bytes",
+                key.len(),
+                key.chars().count(),
+                MAX_KEY_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Options that cannot be combined, with the reason shown when both are given. An entry with
+// a space is an option together with its value.
+const CONFLICTING_OPTIONS: &[(&str, &str, &str)] = &[
+    (
+        "--output json",
+        "--decode",
+        "--decode writes raw bytes, which are not a JSON document",
+    ),
+    (
+        "--output json",
+        "--format yaml",
+        "YAML output is not a JSON document",
+    ),
+    (
+        "--output json",
+        "--format table",
+        "a table is not a JSON document",
+    ),
+    (
+        "--decode",
+        "--preserve-order",
+        "decoded bytes have no fields to order",
+    ),
+    (
+        "--base64",
+        "--preserve-order",
+        "a base64 payload is stored as a string, which has no fields to order",
+    ),
+];
+
+// Options that take the next argument as their value, whatever it looks like. Keep in sync
+// with the options parsed with opt_value_from_str.
+const VALUE_OPTIONS: &[&str] = &[
+    "-o",
+    "--operation",
+    "-k",
+    "--key",
+    "-p",
+    "--payload",
+    "-f",
+    "--file",
+    "-s",
+    "--snapshotid",
+    "--snapshotid2",
+    "--add-prefix",
+    "--bytes",
+    "--chunk-size",
+    "--cond-key",
+    "--cond-value",
+    "--contains",
+    "--count",
+    "--epsilon",
+    "--format",
+    "--idempotency-key",
+    "--input-encoding",
+    "--instance",
+    "--interval",
+    "--keys",
+    "--limit",
+    "--max-concurrency",
+    "--max-keys",
+    "--newkey",
+    "--offset",
+    "--out-dir",
+    "--output",
+    "--output-null",
+    "--pad",
+    "--parallel",
+    "--path",
+    "--redact",
+    "--regex",
+    "--seed",
+    "--sep",
+    "--since-file",
+    "--strip-prefix",
+    "--to",
+    "--type",
+    "--where",
+    "--width",
+];
+
+// Options in `args` with their value, if they take one. Values are skipped rather than read
+// as options, so a payload such as "-p --decode" does not count as --decode.
+fn given_options<'a>(args: &[&'a str]) -> Vec<(&'a str, Option<&'a str>)> {
+    let mut given = Vec::new();
+    let mut rest = args.iter();
+    while let Some(&arg) = rest.next() {
+        match VALUE_OPTIONS.contains(&arg) {
+            true => given.push((arg, rest.next().copied())),
+            false => given.push((arg, None)),
+        }
+    }
+    given
+}
+
+fn check_conflicting_options(args: &[&str]) -> Result<(), ToolError> {
+    let options = given_options(args);
+    let given = |option: &str| match option.split_once(' ') {
+        Some((name, value)) => options.contains(&(name, Some(value))),
+        None => options.iter().any(|(name, _)| *name == option),
+    };
+    for (first, second, reason) in CONFLICTING_OPTIONS {
+        if given(first) && given(second) {
+            return Err(ToolError::with_detail(
+                ToolErrorCode::ConflictingOptions,
+                format!("{} conflicts with {}: {}", first, second, reason),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Temporary key written and removed again by selftest
+const SELFTEST_KEY: &str = "__kvs_tool_selftest__";
+
+// Metadata key holding the idempotency keys of mutations that already succeeded, so a
+// retried request is a no-op even when it runs through another wrapper instance. It is a
+// regular key, so listkeys, export and reset see it like any other.
+const APPLIED_IDS_KEY: &str = "__kvs_tool_applied_ids__";
+
+// Operations that change the store and therefore honour --backup
+const MUTATING_OPERATIONS: &[&str] = &[
+    "setkey",
+    "removekey",
+    "reset",
+    "snapshotrestore",
+    "createtestdata",
+    "apply",
+    "import",
+    "swap",
+    "patch",
+    "rekey",
+    "setif",
+    "trimarrays",
+    "compact",
+    "renamefield",
+    "copy",
+    "seeddefaults",
+];
+
+// Prior state of the keys a multi-step operation touches. Dropping the guard while it is
+// still armed, e.g. when a panic unwinds through the operation, writes that state back, so a
+// panic leaves the store as the error path (rollback) would.
+struct RollbackGuard<'a, K: KvsTrait + ?Sized> {
+    kvs: &'a K,
+    prior: Vec<(String, Option<KvsValue>)>,
+    armed: bool,
+}
+
+impl<'a, K: KvsTrait + ?Sized> RollbackGuard<'a, K> {
+    fn new(kvs: &'a K) -> Self {
+        RollbackGuard {
+            kvs,
+            prior: Vec::new(),
+            armed: true,
+        }
+    }
+
+    // Reads a key's current value, or its absence, before the first write to it
+    fn capture(&mut self, key: &str) -> Result<(), ErrorCode> {
+        if self.prior.iter().any(|(captured, _)| captured == key) {
+            return Ok(());
+        }
+        let prior = match self.kvs.key_exists(key)? {
+            true => Some(self.kvs.get_value_raw(key)?),
+            false => None,
+        };
+        self.record(key, prior);
+        Ok(())
+    }
+
+    // Like capture, for an operation that has already read the prior state
+    fn record(&mut self, key: &str, prior: Option<KvsValue>) {
+        if !self.prior.iter().any(|(captured, _)| captured == key) {
+            self.prior.push((key.to_string(), prior));
+        }
+    }
+
+    fn commit(mut self) {
+        self.armed = false;
+    }
+
+    // Restores the captured state and returns the error to report for `cause`. If some keys
+    // cannot be restored the store is left partly written, so the error names them.
+    fn rollback(mut self, cause: impl Into<ToolError>) -> ToolError {
+        self.armed = false;
+        let cause = cause.into();
+        let unrestored = self.restore();
+        if unrestored.is_empty() {
+            return cause;
+        }
+        let reason = match &cause.detail {
+            Some(detail) => detail.clone(),
+            None => format!("{:?}", cause.code),
+        };
+        let keys: Vec<String> = unrestored.iter().map(|key| format!("'{}'", key)).collect();
+        ToolError::with_detail(
+            cause.code,
+            format!("{}; rollback could not restore {}", reason, keys.join(", ")),
+        )
+    }
+
+    // Replays captured state newest first, returning the keys whose restore failed
+    fn restore(&mut self) -> Vec<String> {
+        let mut unrestored = Vec::new();
+        while let Some((key, prior)) = self.prior.pop() {
+            let restored = match prior {
+                Some(value) => self.kvs.set_value(&key, value),
+                // The write that failed may or may not have created the key
+                None => match self.kvs.key_exists(&key) {
+                    Ok(true) => self.kvs.remove_key(&key),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                },
+            };
+            if restored.is_err() {
+                unrestored.push(key);
+            }
+        }
+        unrestored
+    }
+}
+
+impl<K: KvsTrait + ?Sized> Drop for RollbackGuard<'_, K> {
+    fn drop(&mut self) {
+        // Best effort: there is no caller left to report a failed restore to
+        if self.armed {
+            let _ = self.restore();
+        }
+    }
+}
+
+// Writes buffered on top of a store until commit, with reads seeing the buffered writes.
+// Once committed or rolled back the transaction is closed and calls go straight to the store.
+// Dropping an open transaction rolls it back.
+struct Transaction<'a, K: KvsTrait + ?Sized> {
+    kvs: &'a K,
+    // Buffered writes in call order; None removes the key
+    pending: Vec<(String, Option<KvsValue>)>,
+    open: bool,
+    // Open transaction count of the wrapper that began this one, if any
+    open_count: Option<&'a AtomicUsize>,
+}
+
+impl<'a, K: KvsTrait + ?Sized> Transaction<'a, K> {
+    fn begin(kvs: &'a K) -> Self {
+        Transaction {
+            kvs,
+            pending: Vec::new(),
+            open: true,
+            open_count: None,
+        }
+    }
+
+    // Transaction counted in `open_count` until it is closed
+    fn begin_counted(kvs: &'a K, open_count: &'a AtomicUsize) -> Self {
+        open_count.fetch_add(1, Ordering::SeqCst);
+        Transaction {
+            kvs,
+            pending: Vec::new(),
+            open: true,
+            open_count: Some(open_count),
+        }
+    }
+
+    fn buffered(&self, key: &str) -> Option<&Option<KvsValue>> {
+        self.pending
+            .iter()
+            .rev()
+            .find(|(written, _)| written == key)
+            .map(|(_, value)| value)
+    }
+
+    fn get_value_raw(&self, key: &str) -> Result<KvsValue, ErrorCode> {
+        match self.buffered(key) {
+            Some(Some(value)) => Ok(value.clone()),
+            Some(None) => Err(ErrorCode::KeyNotFound),
+            None => self.kvs.get_value_raw(key),
+        }
+    }
+
+    fn set_value(&mut self, key: &str, value: KvsValue) -> Result<(), ErrorCode> {
+        match self.open {
+            true => {
+                self.pending.push((key.to_string(), Some(value)));
+                Ok(())
+            }
+            false => self.kvs.set_value(key, value),
+        }
+    }
+
+    fn remove_key(&mut self, key: &str) -> Result<(), ErrorCode> {
+        match self.open {
+            true => {
+                self.pending.push((key.to_string(), None));
+                Ok(())
+            }
+            false => self.kvs.remove_key(key),
+        }
+    }
+
+    // Resetting the store under buffered writes would leave reads showing writes to keys
+    // that no longer exist, so the transaction has to end first
+    fn ensure_closed(&self, operation: &str) -> Result<(), ToolError> {
+        match self.open {
+            true => Err(open_transaction_error(operation)),
+            false => Ok(()),
+        }
+    }
+
+    fn reset(&self) -> Result<(), ToolError> {
+        self.ensure_closed("reset")?;
+        Ok(self.kvs.reset()?)
+    }
+
+    fn snapshot_restore(&self, id: u32) -> Result<(), ToolError> {
+        self.ensure_closed("restore a snapshot")?;
+        Ok(self.kvs.snapshot_restore(id)?)
+    }
+
+    // Writes the buffered changes in order. On failure the rest stays buffered and the
+    // transaction stays open.
+    fn commit(&mut self) -> Result<(), ErrorCode> {
+        while !self.pending.is_empty() {
+            match &self.pending[0] {
+                (key, Some(value)) => self.kvs.set_value(key, value.clone())?,
+                (key, None) => self.kvs.remove_key(key)?,
+            }
+            self.pending.remove(0);
+        }
+        self.close();
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        self.pending.clear();
+        self.close();
+    }
+
+    fn close(&mut self) {
+        if self.open {
+            self.open = false;
+            if let Some(open_count) = self.open_count {
+                open_count.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl<K: KvsTrait + ?Sized> Drop for Transaction<'_, K> {
+    fn drop(&mut self) {
+        self.rollback();
+    }
+}
+
+fn open_transaction_error(operation: &str) -> ToolError {
+    ToolError::with_detail(
+        ErrorCode::ResourceBusy,
+        format!(
+            "Cannot {} inside an open transaction: commit or roll back first",
+            operation
+        ),
+    )
+}
+
+// Wrapper to invoke kvs_tool operations
+struct KvsToolWrapper<K: KvsTrait + ?Sized = dyn KvsTrait> {
+    kvs: Arc<K>,
+    // Serializes updates of the applied idempotency keys, which live in the store under
+    // APPLIED_IDS_KEY so that every wrapper over the same backend sees them
+    applied_ids: Mutex<()>,
+    // Variables for --expand-env; None reads the process environment
+    env: Option<HashMap<String, String>>,
+    sink: Arc<dyn OutputSink>,
+    clock: Arc<dyn Clock>,
+    // Operations this deployment may dispatch; None allows all but the denied ones
+    allowed_operations: Option<HashSet<String>>,
+    denied_operations: HashSet<String>,
+    // Other store instances reachable by id, e.g. as the target of cloneto
+    instances: HashMap<u32, Arc<dyn KvsTrait + Send + Sync>>,
+    // Tolerance for numbers when plan, apply, assert, expect and reconcile compare values;
+    // --epsilon overrides it per call. 0 compares exactly.
+    epsilon: f64,
+    // Field order of payloads written with --preserve-order, by key
+    field_orders: Mutex<HashMap<String, FieldOrder>>,
+    // Held shared by every operation and exclusively by reset and snapshotrestore, so those
+    // never run while another operation is in flight. Lock ordering: taken first, before
+    // applied_ids or field_orders, and never acquired while holding either of them.
+    store_lock: RwLock<()>,
+    // Transactions begun on this wrapper and not yet committed or rolled back; reset and
+    // snapshotrestore are refused while any is open
+    open_transactions: AtomicUsize,
+    // Operation run when the arguments name none, instead of failing
+    default_operation: Option<String>,
+}
+
+impl<K: KvsTrait + Send + Sync> KvsToolWrapper<K> {
+    // Wrapper that can be shared across threads and called concurrently.
+    // Single-call operations (getkey, setkey, removekey, listkeys, export, namespaces,
+    // oversized) are safe to interleave. Multi-step operations (swap, patch, import, apply,
+    // --only-if-changed, --verify-after-write, --idempotency-key) read and then write, so a
+    // concurrent writer on the same keys can slip in between the steps. reset and
+    // snapshotrestore wait for the operations in flight and block new ones until they finish.
+    fn shared(kvs: Arc<K>) -> Arc<Self> {
+        Arc::new(Self::from_arc(kvs))
+    }
+}
+
+impl<K: KvsTrait + ?Sized> KvsToolWrapper<K> {
+    fn new(kvs: Box<K>) -> Self {
+        Self::from_arc(Arc::from(kvs))
+    }
+
+    fn from_arc(kvs: Arc<K>) -> Self {
+        KvsToolWrapper {
+            kvs,
+            applied_ids: Mutex::new(()),
+            env: None,
+            sink: Arc::new(StdoutSink),
+            clock: Arc::new(SystemClock),
+            allowed_operations: None,
+            denied_operations: HashSet::new(),
+            instances: HashMap::new(),
+            epsilon: 0.0,
+            field_orders: Mutex::new(HashMap::new()),
+            store_lock: RwLock::new(()),
+            open_transactions: AtomicUsize::new(0),
+            default_operation: None,
+        }
+    }
+
+    fn with_env(mut self, env: HashMap<String, String>) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    fn with_sink(mut self, sink: Arc<dyn OutputSink>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn with_allowed_operations(mut self, operations: &[&str]) -> Self {
+        self.allowed_operations = Some(operations.iter().map(|op| op.to_string()).collect());
+        self
+    }
+
+    fn with_denied_operations(mut self, operations: &[&str]) -> Self {
+        self.denied_operations = operations.iter().map(|op| op.to_string()).collect();
+        self
+    }
+
+    fn with_instance(mut self, id: u32, kvs: Arc<dyn KvsTrait + Send + Sync>) -> Self {
+        self.instances.insert(id, kvs);
+        self
+    }
+
+    fn with_epsilon(mut self, epsilon: f64) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    fn with_default_operation(mut self, operation: &str) -> Self {
+        self.default_operation = Some(operation.to_string());
+        self
+    }
+
+    // Reads a key into a concrete Rust type, for library users that would otherwise parse the
+    // output of getkey. A value of another type fails with ConversionFailed.
+    fn get_typed<T>(&self, key: &str) -> Result<T, ErrorCode>
+    where
+        T: for<'a> TryFrom<&'a KvsValue>,
+    {
+        let _guard = self
+            .store_lock
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let value = self.kvs.get_value_raw(key)?;
+        T::try_from(&value).map_err(|_| ErrorCode::ConversionFailed)
+    }
+
+    // Flushes buffered writes of the store and of every registered instance, returning the
+    // first deferred error. Dropping the wrapper without calling this may lose writes held
+    // back by a BatchedKvs.
+    fn finish(self) -> Result<(), ErrorCode> {
+        self.kvs.flush()?;
+        self.instances
+            .values()
+            .try_for_each(|instance| instance.flush())
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        match &self.env {
+            Some(env) => env.get(name).cloned(),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    // Replaces ${VAR} placeholders. Unresolved ones stay literal unless strict.
+    fn expand_env(&self, text: &str, strict: bool) -> Result<String, ToolError> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start + 2..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 2..start + 2 + len];
+            expanded.push_str(&rest[..start]);
+            match self.env_var(name) {
+                Some(value) => expanded.push_str(&value),
+                None if strict => {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::UnmappedError,
+                        format!("Unresolved environment variable '{}'", name),
+                    ))
+                }
+                None => expanded.push_str(&rest[start..start + 3 + len]),
+            }
+            rest = &rest[start + 3 + len..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    // Expands placeholders in every string of a value, leaving the JSON structure untouched
+    fn expand_env_value(&self, value: KvsValue, strict: bool) -> Result<KvsValue, ToolError> {
+        Ok(match value {
+            KvsValue::String(s) => KvsValue::String(self.expand_env(&s, strict)?),
+            KvsValue::Array(arr) => KvsValue::Array(
+                arr.into_iter()
+                    .map(|v| self.expand_env_value(v, strict))
+                    .collect::<Result<_, _>>()?,
+            ),
+            KvsValue::Object(obj) => KvsValue::Object(
+                obj.into_iter()
+                    .map(|(k, v)| Ok((k, self.expand_env_value(v, strict)?)))
+                    .collect::<Result<_, ToolError>>()?,
+            ),
+            other => other,
+        })
+    }
+
+    fn already_applied(&self, id: Option<&str>) -> Result<bool, ErrorCode> {
+        match id {
+            Some(id) => Ok(self.load_applied_ids()?.iter().any(|applied| applied == id)),
+            None => Ok(false),
+        }
+    }
+
+    fn record_applied(&self, id: Option<String>) -> Result<(), ErrorCode> {
+        if let Some(id) = id {
+            let _guard = self
+                .applied_ids
+                .lock()
+                .map_err(|_| ErrorCode::UnmappedError)?;
+            let mut ids = self.load_applied_ids()?;
+            if !ids.contains(&id) {
+                ids.push(id);
+                self.kvs.set_value(
+                    APPLIED_IDS_KEY,
+                    KvsValue::Array(ids.into_iter().map(KvsValue::String).collect()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Idempotency keys recorded in the store; none are recorded until the first one is
+    fn load_applied_ids(&self) -> Result<Vec<String>, ErrorCode> {
+        match self.kvs.get_value_raw(APPLIED_IDS_KEY) {
+            Ok(KvsValue::Array(ids)) => ids
+                .into_iter()
+                .map(|id| match id {
+                    KvsValue::String(id) => Ok(id),
+                    _ => Err(ErrorCode::ConversionFailed),
+                })
+                .collect(),
+            Ok(_) => Err(ErrorCode::ConversionFailed),
+            Err(ErrorCode::KeyNotFound) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Transaction over this wrapper's store; reset and snapshotrestore dispatched through
+    // the wrapper fail until it is committed or rolled back
+    fn begin_transaction(&self) -> Transaction<'_, K> {
+        Transaction::begin_counted(&*self.kvs, &self.open_transactions)
+    }
+
+    fn ensure_no_open_transaction(&self, operation: &str) -> Result<(), ToolError> {
+        match self.open_transactions.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            _ => Err(open_transaction_error(operation)),
+        }
+    }
+
+    // Diffs the desired state against the store using reads only
+    fn plan_changes(
+        &self,
+        desired: &HashMap<String, KvsValue>,
+        epsilon: f64,
+    ) -> Result<ChangePlan, ErrorCode> {
+        plan_store_changes(&*self.kvs, desired, epsilon)
+    }
+
+    // Tolerance given with --epsilon, or the one the wrapper was built with
+    fn parse_epsilon(&self, pico_args: &mut pico_args::Arguments) -> Result<f64, ToolError> {
+        let epsilon: Option<f64> = pico_args
+            .opt_value_from_str("--epsilon")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        match epsilon {
+            Some(epsilon) if !(epsilon >= 0.0 && epsilon.is_finite()) => {
+                Err(ToolError::with_detail(
+                    ErrorCode::UnmappedError,
+                    format!("Invalid --epsilon '{}' (use a finite number >= 0)", epsilon),
+                ))
+            }
+            Some(epsilon) => Ok(epsilon),
+            None => Ok(self.epsilon),
+        }
+    }
+
+    // Reads the key-value file of a snapshot. Snapshots are numbered from 1 to snapshot_count;
+    // id 0 is the live store, not a snapshot, and is rejected like any id out of range.
+    fn read_snapshot(&self, id: u32) -> Result<HashMap<String, KvsValue>, ToolError> {
+        if id == 0 || id as usize > self.kvs.snapshot_count() {
+            return Err(ErrorCode::InvalidSnapshotId.into());
+        }
+        read_key_value_file(&self.kvs.get_kvs_filename(id))
+    }
+
+    fn require_snapshots(&self) -> Result<(), ToolError> {
+        if !self.kvs.supports_snapshots() {
+            return Err(ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                "Snapshots are not supported by this store",
+            ));
+        }
+        Ok(())
+    }
+
+    // Reads every key with its value
+    fn read_all(&self) -> Result<HashMap<String, KvsValue>, ErrorCode> {
+        let mut values = HashMap::new();
+        for key in self.kvs.get_all_keys()? {
+            let value = self.kvs.get_value_raw(&key)?;
+            values.insert(key, value);
+        }
+        Ok(values)
+    }
+
+    // Reads the comma-separated --keys, returning their values in the same order.
+    // --parallel <n> spreads the reads over n threads if the store is thread-safe.
+    fn getmany(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let keys: String = pico_args
+            .opt_value_from_str("--keys")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let workers: usize = pico_args
+            .opt_value_from_str("--parallel")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or(1);
+        let keys: Vec<&str> = keys.split(',').collect();
+        let values = match self.kvs.thread_safe() {
+            Some(kvs) if workers > 1 => {
+                let chunk_size = keys.len().div_ceil(workers);
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = keys
+                        .chunks(chunk_size)
+                        .map(|chunk| {
+                            scope.spawn(move || {
+                                chunk
+                                    .iter()
+                                    .map(|key| kvs.get_value_string(key))
+                                    .collect::<Vec<_>>()
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .flat_map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or_else(|_| vec![Err(ErrorCode::UnmappedError)])
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })?
+            }
+            _ => keys
+                .iter()
+                .map(|key| self.kvs.get_value_string(key))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        Ok(OperationOutcome::Strings(values))
+    }
+
+    // Keys whose value is larger than --bytes, largest first
+    fn oversized(
+        &self,
+        pico_args: &mut pico_args::Arguments,
+    ) -> Result<OperationOutcome, ToolError> {
+        let threshold: usize = pico_args
+            .opt_value_from_str("--bytes")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let mut sizes: Vec<(String, usize)> = Vec::new();
+        for key in self.kvs.get_all_keys()? {
+            let size = estimated_size(&self.kvs.get_value_raw(&key)?);
+            if size > threshold {
+                sizes.push((key, size));
+            }
+        }
+        sizes.sort_by(|(ka, a), (kb, b)| b.cmp(a).then_with(|| ka.cmp(kb)));
+        Ok(OperationOutcome::Sizes(sizes))
+    }
+
+    // Applies an RFC 6902 patch file to one value, or without -k to the whole store as one object
+    // with a member per key, as exportdiff writes it. Nothing is written unless every op succeeds.
+    fn patch(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let key: Option<String> = pico_args
+            .opt_value_from_str(["-k", "--key"])
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let file: String = pico_args
+            .opt_value_from_str(["-f", "--file"])
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let content = fs::read_to_string(&file).map_err(|e| {
+            ToolError::with_detail(ErrorCode::FileNotFound, format!("{}: {}", file, e))
+        })?;
+        let ops = content.parse::<JsonValue>().map_err(|e| {
+            ToolError::with_detail(ErrorCode::JsonParserError, format!("{}: {}", file, e))
+        })?;
+        let JsonValue::Array(ops) = ops else {
+            return Err(ToolError::with_detail(
+                ErrorCode::JsonParserError,
+                format!("{}: expected a JSON array of patch operations", file),
+            ));
+        };
+        let Some(key) = key else {
+            return self.patch_store(&ops);
+        };
+        let mut value = self.kvs.get_value_raw(&key)?;
+        for op in &ops {
+            apply_patch_op(&mut value, op)?;
+        }
+        self.kvs.set_value(&key, value)?;
+        Ok(OperationOutcome::Done)
+    }
+
+    // Whole-store patch: only the keys whose value the ops changed are written or removed,
+    // restoring the touched keys if a write fails
+    fn patch_store(&self, ops: &[JsonValue]) -> Result<OperationOutcome, ToolError> {
+        let current = self.read_all()?;
+        let mut store = KvsValue::Object(current.clone());
+        for op in ops {
+            apply_patch_op(&mut store, op)?;
+        }
+        let KvsValue::Object(desired) = store else {
+            return Err(ToolError::with_detail(
+                ErrorCode::ValidationFailed,
+                "Patch replaced the store with a value that is not an object",
+            ));
+        };
+        let mut changed: Vec<&String> = desired
+            .iter()
+            .filter(|(key, value)| !current.get(*key).is_some_and(|old| kvs_eq(old, value)))
+            .map(|(key, _)| key)
+            .collect();
+        let mut removed: Vec<&String> = current
+            .keys()
+            .filter(|key| !desired.contains_key(*key))
+            .collect();
+        changed.sort();
+        removed.sort();
+        let mut guard = RollbackGuard::new(&*self.kvs);
+        for key in changed.iter().chain(&removed) {
+            guard.record(key, current.get(*key).cloned());
+        }
+        let writes = || -> Result<(), ErrorCode> {
+            for key in &changed {
+                self.kvs.set_value(key, desired[*key].clone())?;
+            }
+            for key in &removed {
+                self.kvs.remove_key(key)?;
+            }
+            Ok(())
+        };
+        if let Err(e) = writes() {
+            return Err(guard.rollback(e));
+        }
+        guard.commit();
+        Ok(OperationOutcome::Done)
+    }
+
+    // Writes the store to the sink as a JSON object, one member per line as each key is read,
+    // so memory stays bounded by a single value. Each member is held back until the next one
+    // arrives to know whether it needs a trailing comma.
+    fn stream_json(&self, redact: &HashSet<String>) -> Result<(), ToolError> {
+        self.sink.write_line("{");
+        let mut pending: Option<String> = None;
+        for key in self.kvs.get_all_keys()? {
+            let key_json = JsonValue::String(key.clone())
+                .stringify()
+                .map_err(|_| ErrorCode::JsonGeneratorError)?;
+            let mut value = self.kvs.get_value_raw(&key)?;
+            redact_entry(&key, &mut value, redact);
+            let value_json = render_json_value(&value, false)?;
+            if let Some(member) = pending.replace(format!("{}:{}", key_json, value_json)) {
+                self.sink.write_line(&format!("{},", member));
+            }
+        }
+        if let Some(member) = pending {
+            self.sink.write_line(&member);
+        }
+        self.sink.write_line("}");
+        Ok(())
+    }
+
+    // Writes an aligned KEY/VALUE table to the sink, eliding value previews longer than
+    // `width` characters with a trailing "…"
+    fn write_key_table(&self, keys: &[String], width: usize) -> Result<(), ToolError> {
+        let key_width = keys
+            .iter()
+            .map(|key| key.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("KEY".len());
+        self.sink
+            .write_line(&format!("{:<key_width$}  VALUE", "KEY"));
+        for key in keys {
+            let json = render_json_value(&self.kvs.get_value_raw(key)?, false)?;
+            let preview = match json.chars().count() > width {
+                true => format!("{}…", json.chars().take(width - 1).collect::<String>()),
+                false => json,
+            };
+            self.sink
+                .write_line(&format!("{:<key_width$}  {}", key, preview));
+        }
+        Ok(())
+    }
+
+    fn export(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let format: String = pico_args
+            .opt_value_from_str("--format")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or_else(|| "json".to_string());
+        let sort_keys = pico_args.contains("--sort-keys");
+        let chunk_size: Option<usize> = pico_args
+            .opt_value_from_str("--chunk-size")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let out_dir: String = pico_args
+            .opt_value_from_str("--out-dir")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or_else(|| ".".to_string());
+        let redact = parse_redact(pico_args)?;
+        let fail_on_empty = pico_args.contains("--fail-on-empty");
+        let render = |values: &HashMap<String, KvsValue>| -> Result<String, ToolError> {
+            match format.as_str() {
+                "json" => Ok(render_json(values, sort_keys)?),
+                "yaml" => Ok(render_yaml(values)),
+                _ => Err(ToolError::with_detail(
+                    ErrorCode::UnmappedError,
+                    format!("Unsupported export format '{}' (use json or yaml)", format),
+                )),
+            }
+        };
+        let mut values = self.read_all()?;
+        check_not_empty(fail_on_empty, values.is_empty())?;
+        for (key, value) in values.iter_mut() {
+            redact_entry(key, value, &redact);
+        }
+        let Some(chunk_size) = chunk_size else {
+            return Ok(OperationOutcome::Rendered(render(&values)?));
+        };
+        if chunk_size == 0 {
+            return Err(ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                "--chunk-size must be at least 1",
+            ));
+        }
+        // Sorted keys make each chunk's contents stable across runs
+        let mut keys: Vec<String> = values.keys().cloned().collect();
+        keys.sort();
+        let mut chunks = Vec::new();
+        for (index, chunk_keys) in keys.chunks(chunk_size).enumerate() {
+            let chunk: HashMap<String, KvsValue> = chunk_keys
+                .iter()
+                .filter_map(|key| values.remove_entry(key))
+                .collect();
+            let name = format!("export_{}.{}", index, format);
+            let path = std::path::Path::new(&out_dir).join(&name);
+            write_output_file(&path.to_string_lossy(), &render(&chunk)?)?;
+            chunks.push(name);
+        }
+        // The index lists chunk file names relative to the output directory, in order
+        let index = JsonValue::Array(chunks.iter().cloned().map(JsonValue::String).collect())
+            .stringify()
+            .map_err(|_| ErrorCode::JsonGeneratorError)?;
+        let index_path = std::path::Path::new(&out_dir).join("export_index.json");
+        write_output_file(&index_path.to_string_lossy(), &index)?;
+        Ok(OperationOutcome::Strings(chunks))
+    }
+
+    // Sets every entry of a key/value file in sorted key order, stopping at the first failure.
+    // With --merge the files given after the options (after -f, if present) are combined first.
+    fn import(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let file: Option<String> = pico_args
+            .opt_value_from_str(["-f", "--file"])
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let merge = pico_args.contains("--merge");
+        let no_clobber = pico_args.contains("--no-clobber");
+        let max_keys: Option<usize> = pico_args
+            .opt_value_from_str("--max-keys")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let since_file: Option<String> = pico_args
+            .opt_value_from_str("--since-file")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let max_concurrency: usize = pico_args
+            .opt_value_from_str("--max-concurrency")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or(1);
+        let encoding = InputEncoding::parse(pico_args)?;
+        let entries = match merge {
+            false => read_import_entries(&file.ok_or(ErrorCode::UnmappedError)?, encoding)?,
+            true => {
+                // Positional arguments are only taken once every option has been consumed
+                let mut files: Vec<String> = file.into_iter().collect();
+                while let Some(path) = pico_args
+                    .opt_free_from_str()
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                {
+                    files.push(path);
+                }
+                if files.is_empty() {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::UnmappedError,
+                        "--merge needs at least one file",
+                    ));
+                }
+                read_merged_entries(&files, encoding)?
+            }
+        };
+        // Reject the whole file up front rather than failing halfway through
+        for (key, _) in &entries {
+            check_key_length(key)?;
+        }
+        // A key listed several times ends up with its last value
+        let finals: HashMap<&str, &KvsValue> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+        // Content hashes recorded by the previous run; keys with an unchanged hash are skipped
+        let previous_hashes = match &since_file {
+            Some(path) => read_hash_file(path)?,
+            None => HashMap::new(),
+        };
+        let mut pending: Vec<&(String, KvsValue)> = entries
+            .iter()
+            .filter(|(key, _)| previous_hashes.get(key) != Some(&kvs_hash(finals[key.as_str()])))
+            .collect();
+        // Existing keys are looked up once each, in file order, before anything is written
+        if no_clobber {
+            let mut checked = HashSet::new();
+            let mut existing = HashSet::new();
+            for (key, _) in &pending {
+                if checked.insert(key.as_str()) && self.kvs.key_exists(key)? {
+                    self.sink
+                        .write_line(&format!("skipped '{}': already exists", key));
+                    existing.insert(key.as_str());
+                }
+            }
+            pending.retain(|(key, _)| !existing.contains(key.as_str()));
+        }
+        let applied = max_keys.map_or(pending.len(), |max| pending.len().min(max));
+        self.write_entries(&pending[..applied], max_concurrency)?;
+        if let Some(max) = max_keys.filter(|max| pending.len() > *max) {
+            return Err(ToolError::with_detail(
+                ErrorCode::QuotaExceeded,
+                format!(
+                    "Import aborted after {} keys: file has more than --max-keys {}",
+                    applied, max
+                ),
+            ));
+        }
+        // Only keys that were written or already up to date count as imported; a key skipped by
+        // --no-clobber is left out so a later run still imports it
+        if let Some(path) = &since_file {
+            let written: HashSet<&str> = pending[..applied]
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect();
+            let hashes = finals
+                .iter()
+                .map(|(key, value)| (key.to_string(), kvs_hash(value)))
+                .filter(|(key, hash)| {
+                    written.contains(key.as_str()) || previous_hashes.get(key) == Some(hash)
+                })
+                .collect();
+            write_hash_file(path, &hashes)?;
+        }
+        Ok(OperationOutcome::Imported(applied))
+    }
+
+    // Writes entries in order, or with up to `workers` threads on a thread-safe store. Entries
+    // are partitioned by key so one worker writes all entries of a key, in their original order,
+    // and the last entry of a key always wins. Entries of different keys have no ordering.
+    fn write_entries(
+        &self,
+        entries: &[&(String, KvsValue)],
+        workers: usize,
+    ) -> Result<(), ErrorCode> {
+        let Some(kvs) = self.kvs.thread_safe().filter(|_| workers > 1) else {
+            for (key, value) in entries {
+                self.kvs.set_value(key, value.clone())?;
+            }
+            return Ok(());
+        };
+        let mut partitions: Vec<Vec<&(String, KvsValue)>> = vec![Vec::new(); workers];
+        for entry in entries {
+            let mut hasher = DefaultHasher::new();
+            entry.0.hash(&mut hasher);
+            partitions[(hasher.finish() % workers as u64) as usize].push(entry);
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = partitions
+                .iter()
+                .filter(|partition| !partition.is_empty())
+                .map(|partition| {
+                    scope.spawn(move || {
+                        partition
+                            .iter()
+                            .try_for_each(|(key, value)| kvs.set_value(key, value.clone()))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap_or(Err(ErrorCode::UnmappedError)))
+        })
+    }
+
+    // Compares this store against another instance key by key. The current store is the reference:
+    // the plan lists keys missing on the instance (create), differing (update) and only present on
+    // the instance (delete). Read-only unless --apply, which makes the instance match.
+    fn reconcile(
+        &self,
+        pico_args: &mut pico_args::Arguments,
+    ) -> Result<OperationOutcome, ToolError> {
+        let instance: u32 = pico_args
+            .opt_value_from_str("--instance")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let apply = pico_args.contains("--apply");
+        let epsilon = self.parse_epsilon(pico_args)?;
+        let target = self.instances.get(&instance).ok_or_else(|| {
+            ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                format!("No instance {} configured", instance),
+            )
+        })?;
+        let desired = self.read_all()?;
+        let plan = plan_store_changes(&**target, &desired, epsilon)?;
+        if !apply {
+            return Ok(OperationOutcome::Plan(plan));
+        }
+        for key in plan.create.iter().chain(&plan.update) {
+            target.set_value(key, desired[key].clone())?;
+        }
+        for key in &plan.delete {
+            target.remove_key(key)?;
+        }
+        Ok(OperationOutcome::Applied(plan))
+    }
+
+    // Copies every key into another instance. With --atomic all values are staged first and a
+    // failed write restores the target's previous state, so it gets either everything or nothing.
+    fn clone_to(
+        &self,
+        pico_args: &mut pico_args::Arguments,
+    ) -> Result<OperationOutcome, ToolError> {
+        let instance: u32 = pico_args
+            .opt_value_from_str("--instance")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let atomic = pico_args.contains("--atomic");
+        let target = self.instances.get(&instance).ok_or_else(|| {
+            ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                format!("No instance {} configured", instance),
+            )
+        })?;
+        if !atomic {
+            let mut copied = 0;
+            for key in self.kvs.get_all_keys()? {
+                target.set_value(&key, self.kvs.get_value_raw(&key)?)?;
+                copied += 1;
+            }
+            return Ok(OperationOutcome::Imported(copied));
+        }
+        let staged = self.read_all()?;
+        let mut keys: Vec<&String> = staged.keys().collect();
+        keys.sort();
+        let mut guard = RollbackGuard::new(&**target);
+        let result = keys.iter().try_for_each(|key| {
+            guard.capture(key)?;
+            target.set_value(key, staged[*key].clone())
+        });
+        if let Err(e) = result {
+            return Err(guard.rollback(e));
+        }
+        guard.commit();
+        Ok(OperationOutcome::Imported(keys.len()))
+    }
+
+    // Moves values to new key names built by stripping and/or adding a prefix. Only keys
+    // with the strip prefix are moved; without --strip-prefix every key is.
+    fn rekey(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let strip: String = pico_args
+            .opt_value_from_str("--strip-prefix")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or_default();
+        let add: String = pico_args
+            .opt_value_from_str("--add-prefix")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .unwrap_or_default();
+        let keys = self.kvs.get_all_keys()?;
+        let mut renames: Vec<(String, String)> = keys
+            .iter()
+            .filter_map(|key| {
+                let rest = key.strip_prefix(strip.as_str())?;
+                let new_key = format!("{}{}", add, rest);
+                (new_key != *key).then(|| (key.clone(), new_key))
+            })
+            .collect();
+        renames.sort();
+        // Refuse to overwrite a key that stays in place before anything is moved
+        let moved: HashSet<&String> = renames.iter().map(|(old, _)| old).collect();
+        if let Some((old, new)) = renames
+            .iter()
+            .find(|(_, new)| keys.contains(new) && !moved.contains(new))
+        {
+            return Err(ToolError::with_detail(
+                ErrorCode::ValidationFailed,
+                format!("Cannot move '{}' to '{}': key already exists", old, new),
+            ));
+        }
+        // Read everything before writing, since a new name may be another entry's old name
+        let values = renames
+            .iter()
+            .map(|(old, _)| self.kvs.get_value_raw(old))
+            .collect::<Result<Vec<_>, _>>()?;
+        let new_keys: HashSet<&String> = renames.iter().map(|(_, new)| new).collect();
+        // A new name is either free or another entry's old name, already read above
+        let prior: HashMap<&String, &KvsValue> =
+            renames.iter().map(|(old, _)| old).zip(&values).collect();
+        let mut guard = RollbackGuard::new(&*self.kvs);
+        for (old, new) in &renames {
+            guard.record(new, prior.get(new).map(|&value| value.clone()));
+            guard.record(old, Some(prior[old].clone()));
+        }
+        let moves = || -> Result<(), ErrorCode> {
+            for ((_, new), value) in renames.iter().zip(&values) {
+                self.kvs.set_value(new, value.clone())?;
+            }
+            for (old, _) in &renames {
+                if !new_keys.contains(old) {
+                    self.kvs.remove_key(old)?;
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = moves() {
+            return Err(guard.rollback(e));
+        }
+        guard.commit();
+        Ok(OperationOutcome::Renames(renames))
+    }
+
+    // Exchanges the values of two keys, restoring the first if the second write fails or panics
+    fn swap(&self, pico_args: &mut pico_args::Arguments) -> Result<OperationOutcome, ToolError> {
+        let key_a: String = pico_args
+            .opt_value_from_str(["-k", "--key"])
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let key_b: String = pico_args
+            .opt_value_from_str("--newkey")
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        for key in [&key_a, &key_b] {
+            if !self.kvs.key_exists(key)? {
+                return Err(ToolError::with_detail(
+                    ErrorCode::KeyNotFound,
+                    format!("Cannot swap: key '{}' does not exist", key),
+                ));
+            }
+        }
+        let value_a = self.kvs.get_value_raw(&key_a)?;
+        let value_b = self.kvs.get_value_raw(&key_b)?;
+        self.kvs.set_value(&key_a, value_b)?;
+        // Armed only once the first write succeeded, so a failed first write writes nothing back
+        let mut guard = RollbackGuard::new(&*self.kvs);
+        guard.record(&key_a, Some(value_a.clone()));
+        if let Err(e) = self.kvs.set_value(&key_b, value_a) {
+            return Err(guard.rollback(e));
+        }
+        guard.commit();
+        Ok(OperationOutcome::Done)
+    }
+
+    fn setkey(
+        &self,
+        pico_args: &mut pico_args::Arguments,
+        trace: &Trace,
+    ) -> Result<OperationOutcome, ToolError> {
+        let key: String = pico_args
+            .opt_value_from_str(["-k", "--key"])
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let value: String = pico_args
+            .opt_value_from_str(["-p", "--payload"])
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .ok_or(ErrorCode::UnmappedError)?;
+        let idempotency_key: Option<String> = pico_args
+            .opt_value_from_str("--idempotency-key")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        let only_if_changed = pico_args.contains("--only-if-changed");
+        let expand_env = pico_args.contains("--expand-env");
+        let strict_env = pico_args.contains("--strict-env");
+        let verify_after_write = pico_args.contains("--verify-after-write");
+        // Stores the payload bytes base64-encoded instead of parsing them
+        let base64 = pico_args.contains("--base64");
+        let preserve_order = pico_args.contains("--preserve-order");
+        // --as-<type> pins the top-level type the payload must parse to
+        let mut expected_types = Vec::new();
+        for (flag, type_name) in [
+            ("--as-array", "array"),
+            ("--as-object", "object"),
+            ("--as-number", "number"),
+            ("--as-string", "string"),
+            ("--as-boolean", "boolean"),
+        ] {
+            if pico_args.contains(flag) {
+                expected_types.push(type_name);
+            }
+        }
+        if expected_types.len() > 1 {
+            return Err(ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                format!(
+                    "Conflicting type options: --as-{}",
+                    expected_types.join(", --as-")
+                ),
+            ));
+        }
+        check_key_length(&key)?;
+        if self.already_applied(idempotency_key.as_deref())? {
+            trace.step("idempotency key already applied, skipped");
+            return Ok(OperationOutcome::AlreadyApplied);
+        }
+        let order = match preserve_order {
+            true => json_field_order(&value),
+            false => None,
+        };
+        let kvs_value = if base64 {
+            trace.step("encoded payload as base64");
+            KvsValue::String(base64_encode(value.as_bytes()))
+        } else {
+            parse_payload(value)?
+        };
+        trace.step(&format!("parsed payload as {}", kvs_type_name(&kvs_value)));
+        if let Some(expected) = expected_types.first() {
+            let actual = kvs_type_name(&kvs_value);
+            if actual != *expected {
+                return Err(ToolError::with_detail(
+                    ErrorCode::ConversionFailed,
+                    format!("Payload has type {}, expected {}", actual, expected),
+                ));
+            }
+        }
+        let kvs_value = if expand_env {
+            trace.step("expanded environment variables");
+            self.expand_env_value(kvs_value, strict_env)?
+        } else {
+            kvs_value
+        };
+        // Skip identical writes; a missing key is always written
+        if only_if_changed {
+            if !self.kvs.key_exists(&key)? {
+                trace.step("key did not exist, writing");
+            } else if kvs_eq(&self.kvs.get_value_raw(&key)?, &kvs_value) {
+                trace.step("key existed with an equal value, unchanged, skipped");
+                return Ok(OperationOutcome::Unchanged);
+            } else {
+                trace.step("key existed with a different value, writing");
+            }
+        }
+        if verify_after_write {
+            trace.step("verifying value after write");
+            self.kvs.set_value(&key, kvs_value.clone())?;
+            // Catches backends that silently coerce or truncate what they store
+            let stored = self.kvs.get_value_raw(&key)?;
+            if !kvs_eq(&stored, &kvs_value) {
+                return Err(ToolError::with_detail(
+                    ErrorCode::ValidationFailed,
+                    format!(
+                        "Read-back of '{}' differs from written value: wrote {:?}, read {:?}",
+                        key, kvs_value, stored
+                    ),
+                ));
+            }
+        } else {
+            self.kvs.set_value(&key, kvs_value)?;
+        }
+        self.record_field_order(&key, order)?;
+        self.record_applied(idempotency_key)?;
+        Ok(OperationOutcome::Done)
+    }
+
+    // Remembers the field order of the payload just written to `key`, or forgets the one of
+    // an earlier write when the new payload was not written with --preserve-order
+    fn record_field_order(&self, key: &str, order: Option<FieldOrder>) -> Result<(), ErrorCode> {
+        let mut field_orders = self
+            .field_orders
+            .lock()
+            .map_err(|_| ErrorCode::MutexLockFailed)?;
+        match order {
+            Some(order) => field_orders.insert(key.to_string(), order),
+            None => field_orders.remove(key),
+        };
+        Ok(())
+    }
+
+    // Runs one operation. Under --output json a failure is also written to the sink as a JSON
+    // document, so consumers parse failures the same way as results.
+    fn execute_operation(&self, args: Vec<&str>) -> Result<OperationOutcome, ToolError> {
+        let json_output = args.windows(2).any(|pair| pair == ["--output", "json"]);
+        let result = self.execute_args(args);
+        if let (true, Err(err)) = (json_output, &result) {
+            // Failing to render must not mask the operation's own error
+            if let Ok(line) = render_error_json(err) {
+                self.sink.write_line(&line);
+            }
+        }
+        result
+    }
+
+    // Runs operations in order and stops at the first failure. A leading ["--atomic"] entry
+    // makes the batch all or nothing: on failure every key is put back as it was before the
+    // batch, and keys the batch created are removed. That reads the whole store up front.
+    fn execute_batch(&self, ops: &[Vec<&str>]) -> Result<Vec<OperationOutcome>, ToolError> {
+        let (atomic, ops) = match ops.split_first() {
+            Some((first, rest)) if first.as_slice() == ["--atomic"] => (true, rest),
+            _ => (false, ops),
+        };
+        let mut guard = None;
+        let mut existing = HashSet::new();
+        if atomic {
+            let mut prior = RollbackGuard::new(&*self.kvs);
+            for key in self.kvs.get_all_keys()? {
+                prior.record(&key, Some(self.kvs.get_value_raw(&key)?));
+                existing.insert(key);
+            }
+            guard = Some(prior);
+        }
+        let mut outcomes = Vec::with_capacity(ops.len());
+        for op in ops {
+            match self.execute_operation(op.clone()) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(err) => {
+                    if let Some(mut guard) = guard {
+                        for key in self.kvs.get_all_keys()? {
+                            if !existing.contains(&key) {
+                                guard.record(&key, None);
+                            }
+                        }
+                        return Err(guard.rollback(err));
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        if let Some(guard) = guard {
+            guard.commit();
+        }
+        Ok(outcomes)
+    }
+
+    fn execute_args(&self, args: Vec<&str>) -> Result<OperationOutcome, ToolError> {
+        // Rejected before anything runs rather than letting one option silently win
+        check_conflicting_options(&args)?;
+        // The key for the operation span, read ahead of the operation's own parsing
+        #[cfg(feature = "tracing")]
+        let span_key = args
+            .iter()
+            .position(|arg| *arg == "-k" || *arg == "--key")
+            .and_then(|index| args.get(index + 1))
+            .map(|key| key.to_string());
+        let args: Vec<OsString> = args.into_iter().map(|s| s.into()).collect();
+        let mut pico_args = pico_args::Arguments::from_vec(args);
+
+        if pico_args.contains(["-h", "--help"]) {
+            return Ok(OperationOutcome::Done);
+        }
+        let output: Option<String> = pico_args
+            .opt_value_from_str("--output")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        if let Some(other) = output
+            .as_deref()
+            .filter(|&output| output != "json" && output != "text")
+        {
+            return Err(ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                format!("Unsupported --output '{}' (use text or json)", other),
+            ));
+        }
+
+        let operation: Option<String> = pico_args
+            .opt_value_from_str(["-o", "--operation"])
+            .map_err(|_| ErrorCode::UnmappedError)?
+            .or_else(|| self.default_operation.clone());
+        let op_mode = match operation.as_deref() {
+            Some("getkey") => "getkey",
+            Some("setkey") => "setkey",
+            Some("removekey") => "removekey",
+            Some("listkeys") => "listkeys",
+            Some("reset") => "reset",
+            Some("snapshotcount") => "snapshotcount",
+            Some("snapshotmaxcount") => "snapshotmaxcount",
+            Some("snapshotrestore") => "snapshotrestore",
+            Some("getkvsfilename") => "getkvsfilename",
+            Some("gethashfilename") => "gethashfilename",
+            Some("createtestdata") => "createtestdata",
+            Some("namespaces") => "namespaces",
+            Some("plan") => "plan",
+            Some("apply") => "apply",
+            Some("export") => "export",
+            Some("import") => "import",
+            Some("swap") => "swap",
+            Some("oversized") => "oversized",
+            Some("patch") => "patch",
+            Some("changedsince") => "changedsince",
+            Some("query") => "query",
+            Some("countvalue") => "countvalue",
+            Some("getmany") => "getmany",
+            Some("lint") => "lint",
+            Some("migratefilenames") => "migratefilenames",
+            Some("cloneto") => "cloneto",
+            Some("reconcile") => "reconcile",
+            Some("sample") => "sample",
+            Some("resolve") => "resolve",
+            Some("rekey") => "rekey",
+            Some("assert") => "assert",
+            Some("expect") => "expect",
+            Some("dumpall") => "dumpall",
+            Some("setif") => "setif",
+            Some("trimarrays") => "trimarrays",
+            Some("locate") => "locate",
+            Some("compact") => "compact",
+            Some("checktype") => "checktype",
+            Some("listtyped") => "listtyped",
+            Some("renamefield") => "renamefield",
+            Some("copy") => "copy",
+            Some("watchall") => "watchall",
+            Some("sizereport") => "sizereport",
+            Some("typedrift") => "typedrift",
+            Some("exportdiff") => "exportdiff",
+            Some("dumpsnapshot") => "dumpsnapshot",
+            Some("driftfromdefault") => "driftfromdefault",
+            Some("capabilities") => "capabilities",
+            Some("selftest") => "selftest",
+            Some("setdefault") => "setdefault",
+            Some("grep") => "grep",
+            Some("seeddefaults") => "seeddefaults",
+            Some("getraw") => "getraw",
+            Some("minpatch") => "minpatch",
+            Some("casecollisions") => "casecollisions",
+            Some("sizestats") => "sizestats",
+            _ => return Err(ErrorCode::UnmappedError.into()),
+        };
+        // Checked before anything else so a denied operation never reaches the store
+        let allowed = self
+            .allowed_operations
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(op_mode));
+        if !allowed || self.denied_operations.contains(op_mode) {
+            return Err(ToolError::with_detail(
+                ToolErrorCode::OperationNotPermitted,
+                format!(
+                    "Operation '{}' is not permitted by this deployment",
+                    op_mode
+                ),
+            ));
+        }
+        // Span names are static in tracing, so the operation is recorded as a field
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "kvs_operation",
+            operation = op_mode,
+            key = span_key.as_deref()
+        )
+        .entered();
+
+        let trace = Trace {
+            sink: pico_args
+                .contains("--explain")
+                .then_some(self.sink.as_ref()),
+        };
+        // The lock guards no data, so a panic under it leaves nothing to distrust
+        let exclusive = matches!(op_mode, "reset" | "snapshotrestore");
+        let _exclusive_guard = match exclusive {
+            true => Some(
+                self.store_lock
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner),
+            ),
+            false => None,
+        };
+        let _shared_guard = match exclusive {
+            true => None,
+            false => Some(
+                self.store_lock
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner),
+            ),
+        };
+        // Snapshot first so a bad write can be rolled back with snapshotrestore
+        let backup = pico_args.contains("--backup");
+        let snapshot_id = if backup && MUTATING_OPERATIONS.contains(&op_mode) {
+            let snapshot_id = self.kvs.snapshot_create()?;
+            trace.step(&format!("created backup snapshot {}", snapshot_id));
+            Some(snapshot_id)
+        } else {
+            None
+        };
+        let outcome = self.run_operation(op_mode, pico_args, &trace)?;
+        Ok(match snapshot_id {
+            Some(snapshot_id) => OperationOutcome::BackedUp {
+                snapshot_id,
+                outcome: Box::new(outcome),
+            },
+            None => outcome,
+        })
+    }
+
+    fn run_operation(
+        &self,
+        op_mode: &str,
+        mut pico_args: pico_args::Arguments,
+        trace: &Trace,
+    ) -> Result<OperationOutcome, ToolError> {
+        match op_mode {
+            "getkey" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                // Text printed instead of a null value, so "null" and "no output" differ
+                let null_sentinel: Option<String> = pico_args
+                    .opt_value_from_str("--output-null")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                // Writes the base64-decoded bytes of a string value to the sink
+                let decode = pico_args.contains("--decode");
+                let preserve_order = pico_args.contains("--preserve-order");
+                if !self.kvs.key_exists(&key)? {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::KeyNotFound,
+                        format!("Key '{}' not found", key),
+                    ));
+                }
+                if decode {
+                    let KvsValue::String(encoded) = self.kvs.get_value_raw(&key)? else {
+                        return Err(ToolError::with_detail(
+                            ErrorCode::ConversionFailed,
+                            format!("'{}' is not a string, cannot --decode it", key),
+                        ));
+                    };
+                    let bytes = base64_decode(&encoded).ok_or_else(|| {
+                        ToolError::with_detail(
+                            ErrorCode::ConversionFailed,
+                            format!("'{}' does not hold valid base64", key),
+                        )
+                    })?;
+                    self.sink.write_bytes(&bytes);
+                    return Ok(OperationOutcome::Done);
+                }
+                let _ = self.kvs.is_value_default(&key)?;
+                let _ = self.kvs.get_default_value(&key)?;
+                let value = self.kvs.get_value_string(&key)?;
+                if preserve_order {
+                    let order = self
+                        .field_orders
+                        .lock()
+                        .map_err(|_| ErrorCode::MutexLockFailed)?
+                        .get(&key)
+                        .cloned();
+                    if let Some(order) = order {
+                        let raw = self.kvs.get_value_raw(&key)?;
+                        return Ok(OperationOutcome::Rendered(render_json_ordered(
+                            &raw, &order,
+                        )?));
+                    }
+                }
+                // Only a null value itself is replaced; nulls nested in objects or arrays stay JSON
+                match null_sentinel {
+                    Some(sentinel) if matches!(self.kvs.get_value_raw(&key)?, KvsValue::Null) => {
+                        Ok(OperationOutcome::Rendered(sentinel))
+                    }
+                    _ => Ok(OperationOutcome::Rendered(value)),
+                }
+            }
+            "getraw" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                // The text itself, unquoted and without a trailing newline, for piping
+                match self.kvs.get_value_raw(&key)? {
+                    KvsValue::String(text) => {
+                        self.sink.write_bytes(text.as_bytes());
+                        Ok(OperationOutcome::Done)
+                    }
+                    other => Err(ToolError::with_detail(
+                        ErrorCode::ConversionFailed,
+                        format!(
+                            "'{}' holds a {}, not a string; use getkey instead",
+                            key,
+                            kvs_type_name(&other)
+                        ),
+                    )),
+                }
+            }
+            "setkey" => self.setkey(&mut pico_args, trace),
+            "renamefield" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let path: String = pico_args
+                    .opt_value_from_str("--path")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let new_name: String = pico_args
+                    .opt_value_from_str("--to")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let mut value = self.kvs.get_value_raw(&key)?;
+                rename_field(&mut value, &path, &new_name).map_err(|e| {
+                    ToolError::with_detail(
+                        e.code,
+                        format!("{}: {}", key, e.detail.unwrap_or_default()),
+                    )
+                })?;
+                self.kvs.set_value(&key, value)?;
+                Ok(OperationOutcome::Done)
+            }
+            "watchall" => {
+                let interval: u64 = pico_args
+                    .opt_value_from_str("--interval")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .unwrap_or(1000);
+                let count: usize = pico_args
+                    .opt_value_from_str("--count")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                // Each of the `count` polls is reported against the one before it; the
+                // first against the state when the operation started
+                let mut previous = self.read_all()?;
+                let mut changes = Vec::new();
+                for _ in 0..count {
+                    self.clock.sleep(Duration::from_millis(interval));
+                    let current = self.read_all()?;
+                    for line in store_changes(&previous, &current)? {
+                        self.sink.write_line(&line);
+                        changes.push(line);
+                    }
+                    previous = current;
+                }
+                Ok(OperationOutcome::Strings(changes))
+            }
+            "sizereport" => {
+                let mut by_type: HashMap<&'static str, (usize, usize)> = HashMap::new();
+                let mut total_bytes = 0;
+                for key in self.kvs.get_all_keys()? {
+                    let value = self.kvs.get_value_raw(&key)?;
+                    let size = estimated_size(&value);
+                    let (count, bytes) = by_type.entry(kvs_type_name(&value)).or_insert((0, 0));
+                    *count += 1;
+                    *bytes += size;
+                    total_bytes += size;
+                }
+                Ok(OperationOutcome::SizeReport {
+                    by_type: in_type_order(by_type),
+                    total_bytes,
+                })
+            }
+            "sizestats" => {
+                let mut sizes = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    sizes.push(estimated_size(&self.kvs.get_value_raw(&key)?));
+                }
+                sizes.sort_unstable();
+                let count = sizes.len();
+                let mean = match count {
+                    0 => 0.0,
+                    _ => sizes.iter().sum::<usize>() as f64 / count as f64,
+                };
+                Ok(OperationOutcome::SizeStats {
+                    count,
+                    min: sizes.first().copied().unwrap_or(0),
+                    max: sizes.last().copied().unwrap_or(0),
+                    mean,
+                    p50: percentile(&sizes, 50),
+                    p95: percentile(&sizes, 95),
+                })
+            }
+            "listtyped" => {
+                let mut typed = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    let type_name = kvs_type_name(&self.kvs.get_value_raw(&key)?);
+                    typed.push((key, type_name));
+                }
+                typed.sort();
+                Ok(OperationOutcome::Types(typed))
+            }
+            "checktype" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let expected: String = pico_args
+                    .opt_value_from_str("--type")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                if !TYPE_NAMES.contains(&expected.as_str()) {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::UnmappedError,
+                        format!(
+                            "Unknown type '{}': expected one of {}",
+                            expected,
+                            TYPE_NAMES.join(", ")
+                        ),
+                    ));
+                }
+                let actual = kvs_type_name(&self.kvs.get_value_raw(&key)?);
+                if actual != expected {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::ValidationFailed,
+                        format!("'{}' has type {}, expected {}", key, actual, expected),
+                    ));
+                }
+                Ok(OperationOutcome::Done)
+            }
+            "locate" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let value = self.kvs.get_value_raw(&key)?;
+                Ok(OperationOutcome::Located {
+                    value,
+                    filename: self.kvs.get_kvs_filename(0),
+                })
+            }
+            "setdefault" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let payload: String = pico_args
+                    .opt_value_from_str(["-p", "--payload"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                check_key_length(&key)?;
+                self.kvs.set_default_value(&key, parse_payload(payload)?)?;
+                Ok(OperationOutcome::Done)
+            }
+            "seeddefaults" => {
+                // Writes the default of every key still falling back to it, so the stored
+                // values no longer depend on the defaults file
+                let mut seeded = Vec::new();
+                for key in self.kvs.get_default_keys()? {
+                    if self.kvs.is_value_default(&key)? {
+                        self.kvs
+                            .set_value(&key, self.kvs.get_default_value(&key)?)?;
+                        seeded.push(key);
+                    }
+                }
+                seeded.sort();
+                Ok(OperationOutcome::keys(seeded))
+            }
+            "removekey" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let idempotency_key: Option<String> = pico_args
+                    .opt_value_from_str("--idempotency-key")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                if self.already_applied(idempotency_key.as_deref())? {
+                    return Ok(OperationOutcome::AlreadyApplied);
+                }
+                self.kvs.remove_key(&key)?;
+                self.record_applied(idempotency_key)?;
+                Ok(OperationOutcome::Done)
+            }
+            "listkeys" => {
+                let skip_tombstones = pico_args.contains("--skip-tombstones");
+                let fail_on_empty = pico_args.contains("--fail-on-empty");
+                let mut keys = self.kvs.get_all_keys()?;
+                if skip_tombstones {
+                    // Some backends still list removed keys; trust key_exists over the listing
+                    let mut live = Vec::with_capacity(keys.len());
+                    for key in keys {
+                        if self.kvs.key_exists(&key)? {
+                            live.push(key);
+                        }
+                    }
+                    keys = live;
+                }
+                check_not_empty(fail_on_empty, keys.is_empty())?;
+                let offset: Option<usize> = pico_args
+                    .opt_value_from_str("--offset")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let limit: Option<usize> = pico_args
+                    .opt_value_from_str("--limit")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let total = keys.len();
+                if offset.is_some() || limit.is_some() {
+                    // Pages are only stable across calls in a fixed order
+                    keys.sort();
+                    keys = keys
+                        .into_iter()
+                        .skip(offset.unwrap_or(0))
+                        .take(limit.unwrap_or(usize::MAX))
+                        .collect();
+                }
+                if !pico_args.contains("--with-values") {
+                    return Ok(OperationOutcome::Keys { names: keys, total });
+                }
+                let format: Option<String> = pico_args
+                    .opt_value_from_str("--format")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let width: usize = pico_args
+                    .opt_value_from_str("--width")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .unwrap_or(40);
+                match format.as_deref() {
+                    None => {
+                        let mut values = HashMap::new();
+                        for key in keys {
+                            let value = self.kvs.get_value_raw(&key)?;
+                            values.insert(key, value);
+                        }
+                        Ok(OperationOutcome::Values(values))
+                    }
+                    Some("table") if width > 0 => {
+                        keys.sort();
+                        self.write_key_table(&keys, width)?;
+                        Ok(OperationOutcome::Done)
+                    }
+                    Some(other) => Err(ToolError::with_detail(
+                        ErrorCode::UnmappedError,
+                        format!("Unsupported listkeys format '{}' or width {}", other, width),
+                    )),
+                }
+            }
+            "reset" => {
+                self.ensure_no_open_transaction("reset")?;
+                let no_count = pico_args.contains("--no-count");
+                // Count first; afterwards there is nothing left to enumerate
+                let keys_removed = match no_count {
+                    true => None,
+                    false => Some(self.kvs.get_all_keys()?.len()),
+                };
+                let snapshots = self.kvs.snapshot_count();
+                self.kvs.reset()?;
+                Ok(OperationOutcome::Reset {
+                    keys_removed,
+                    snapshots,
+                })
+            }
+            "snapshotcount" => {
+                self.require_snapshots()?;
+                self.kvs.snapshot_count();
+                Ok(OperationOutcome::Done)
+            }
+            "snapshotmaxcount" => {
+                Ok(OperationOutcome::Done)
+            }
+            "snapshotrestore" => {
+                self.ensure_no_open_transaction("restore a snapshot")?;
+                let snapshot_id = parse_snapshot_id(&mut pico_args)?;
+                self.require_snapshots()?;
+                self.kvs.snapshot_restore(snapshot_id)?;
+                Ok(OperationOutcome::Done)
+            }
+            "getkvsfilename" => {
+                let snapshot_id = parse_snapshot_id(&mut pico_args)?;
+                self.kvs.get_kvs_filename(snapshot_id);
+                Ok(OperationOutcome::Done)
+            }
+            "gethashfilename" => {
+                let snapshot_id = parse_snapshot_id(&mut pico_args)?;
+                self.kvs.get_hash_filename(snapshot_id);
+                Ok(OperationOutcome::Done)
+            }
+            "createtestdata" => {
+                let count: Option<usize> = pico_args
+                    .opt_value_from_str("--count")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let seed: u64 = pico_args
+                    .opt_value_from_str("--seed")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .unwrap_or(0);
+                if let Some(count) = count {
+                    let mut state = seed;
+                    let mut type_counts = HashMap::new();
+                    for index in 0..count {
+                        let value = generated_value(&mut state, index);
+                        *type_counts.entry(kvs_type_name(&value)).or_insert(0) += 1;
+                        self.kvs.set_value(&format!("testdata_{}", index), value)?;
+                    }
+                    return Ok(OperationOutcome::TypeCounts(in_type_order(type_counts)));
+                }
+                self.kvs.set_value("number", KvsValue::Number(123.0))?;
+                self.kvs.set_value("bool", KvsValue::Boolean(true))?;
+                self.kvs.set_value("string", KvsValue::String("First".to_string()))?;
+                self.kvs.set_value("null", KvsValue::Null)?;
+                self.kvs.set_value(
+                    "array",
+                    KvsValue::Array(vec![
+                        KvsValue::Number(456.0),
+                        KvsValue::Boolean(false),
+                        KvsValue::String("Second".to_string()),
+                    ]),
+                )?;
+                self.kvs.set_value(
+                    "object",
+                    KvsValue::Object(HashMap::from([
+                        ("sub-number".to_string(), KvsValue::Number(789.0)),
+                        ("sub-bool".to_string(), KvsValue::Boolean(true)),
+                        ("sub-string".to_string(), KvsValue::String("Third".to_string())),
+                        ("sub-null".to_string(), KvsValue::Null),
+                        (
+                            "sub-array".to_string(),
+                            KvsValue::Array(vec![
+                                KvsValue::Number(1246.0),
+                                KvsValue::Boolean(false),
+                                KvsValue::String("Fourth".to_string()),
+                            ]),
+                        ),
+                    ])),
+                )?;
+                Ok(OperationOutcome::Done)
+            }
+            "namespaces" => {
+                let sep: String = pico_args
+                    .opt_value_from_str("--sep")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .unwrap_or_else(|| ".".to_string());
+                if sep.is_empty() {
+                    return Err(ErrorCode::UnmappedError.into());
+                }
+                // Keys without a separator are counted under the "" namespace
+                let mut counts: HashMap<String, usize> = HashMap::new();
+                for key in self.kvs.get_all_keys()? {
+                    let namespace = key.split_once(sep.as_str()).map_or("", |(ns, _)| ns);
+                    *counts.entry(namespace.to_string()).or_insert(0) += 1;
+                }
+                Ok(OperationOutcome::Namespaces(counts))
+            }
+            "plan" => {
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let epsilon = self.parse_epsilon(&mut pico_args)?;
+                let desired = read_key_value_file(&file)?;
+                Ok(OperationOutcome::Plan(
+                    self.plan_changes(&desired, epsilon)?,
+                ))
+            }
+            "apply" => {
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let prune = pico_args.contains("--prune");
+                let epsilon = self.parse_epsilon(&mut pico_args)?;
+                let desired = read_key_value_file(&file)?;
+                // Same diff as "plan", so both always agree on what changes
+                let mut plan = self.plan_changes(&desired, epsilon)?;
+                for key in plan.create.iter().chain(&plan.update) {
+                    self.kvs.set_value(key, desired[key].clone())?;
+                }
+                if prune {
+                    for key in &plan.delete {
+                        self.kvs.remove_key(key)?;
+                    }
+                } else {
+                    plan.delete.clear();
+                }
+                Ok(OperationOutcome::Applied(plan))
+            }
+            "export" => self.export(&mut pico_args),
+            "import" => self.import(&mut pico_args),
+            "swap" => self.swap(&mut pico_args),
+            "copy" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let new_key: String = pico_args
+                    .opt_value_from_str("--newkey")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let no_clobber = pico_args.contains("--no-clobber");
+                check_key_length(&new_key)?;
+                let value = self.kvs.get_value_raw(&key)?;
+                if no_clobber && self.kvs.key_exists(&new_key)? {
+                    self.sink
+                        .write_line(&format!("skipped '{}': already exists", new_key));
+                    return Ok(OperationOutcome::Unchanged);
+                }
+                self.kvs.set_value(&new_key, value)?;
+                Ok(OperationOutcome::Done)
+            }
+            "oversized" => self.oversized(&mut pico_args),
+            "patch" => self.patch(&mut pico_args),
+            "minpatch" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let desired = convert_json_to_kvs(&read_json_file(&file)?);
+                let current = self.kvs.get_value_raw(&key)?;
+                // Printed for review only; "patch" applies it
+                let mut ops = Vec::new();
+                diff_patch_ops("", &current, &desired, &mut ops)?;
+                Ok(OperationOutcome::Rendered(format!("[{}]", ops.join(","))))
+            }
+            "query" => {
+                let filter: String = pico_args
+                    .opt_value_from_str("--where")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let predicate = Predicate::parse(&filter)?;
+                let mut keys = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    if predicate.matches(&self.kvs.get_value_raw(&key)?) {
+                        keys.push(key);
+                    }
+                }
+                keys.sort();
+                Ok(OperationOutcome::keys(keys))
+            }
+            "grep" => {
+                let needle: Option<String> = pico_args
+                    .opt_value_from_str("--contains")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let pattern: Option<String> = pico_args
+                    .opt_value_from_str("--regex")
+                    .map_err(|_| ErrorCode::UnmappedError)?;
+                let matches: Box<dyn Fn(&str) -> bool> = match (needle, pattern) {
+                    (Some(needle), None) => Box::new(move |text| text.contains(&needle)),
+                    (None, Some(pattern)) => {
+                        let regex = regex::Regex::new(&pattern).map_err(|e| {
+                            ToolError::with_detail(
+                                ErrorCode::ValidationFailed,
+                                format!("Invalid --regex '{}': {}", pattern, e),
+                            )
+                        })?;
+                        Box::new(move |text| regex.is_match(text))
+                    }
+                    _ => {
+                        return Err(ToolError::with_detail(
+                            ErrorCode::UnmappedError,
+                            "Expected exactly one of --contains <text> or --regex <pattern>",
+                        ))
+                    }
+                };
+                // Values are searched in their rendered string form, as getkey prints them
+                let mut keys = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    if matches(&self.kvs.get_value_string(&key)?) {
+                        keys.push(key);
+                    }
+                }
+                keys.sort();
+                Ok(OperationOutcome::keys(keys))
+            }
+            "getmany" => self.getmany(&mut pico_args),
+            "cloneto" => self.clone_to(&mut pico_args),
+            "reconcile" => self.reconcile(&mut pico_args),
+            "rekey" => self.rekey(&mut pico_args),
+            "setif" => {
+                let cond_key: String = pico_args
+                    .opt_value_from_str("--cond-key")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let cond_value: String = pico_args
+                    .opt_value_from_str("--cond-value")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let payload: String = pico_args
+                    .opt_value_from_str(["-p", "--payload"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let expected = parse_payload(cond_value)?;
+                let value = parse_payload(payload)?;
+                check_key_length(&key)?;
+                // A missing condition key never matches
+                if !self.kvs.key_exists(&cond_key)?
+                    || !kvs_eq(&self.kvs.get_value_raw(&cond_key)?, &expected)
+                {
+                    return Ok(OperationOutcome::Unchanged);
+                }
+                self.kvs.set_value(&key, value)?;
+                Ok(OperationOutcome::Done)
+            }
+            "dumpall" => {
+                let redact = parse_redact(&mut pico_args)?;
+                let fail_on_empty = pico_args.contains("--fail-on-empty");
+                if pico_args.contains("--stream") {
+                    // Checked up front, as streaming has written the opening brace by the
+                    // time it finds out
+                    if fail_on_empty {
+                        check_not_empty(true, self.kvs.get_all_keys()?.is_empty())?;
+                    }
+                    self.stream_json(&redact)?;
+                    return Ok(OperationOutcome::Done);
+                }
+                let mut values = self.read_all()?;
+                check_not_empty(fail_on_empty, values.is_empty())?;
+                for (key, value) in values.iter_mut() {
+                    redact_entry(key, value, &redact);
+                }
+                Ok(OperationOutcome::Rendered(render_json(&values, false)?))
+            }
+            "dumpsnapshot" => {
+                let snapshot_id = parse_snapshot_id(&mut pico_args)?;
+                // Read from the snapshot's file, unlike dumpall which sees the live state
+                let values = self.read_snapshot(snapshot_id)?;
+                let object = values
+                    .iter()
+                    .map(|(key, value)| (key.clone(), convert_kvs_to_json(value)))
+                    .collect();
+                let pretty = JsonValue::Object(object)
+                    .format()
+                    .map_err(|_| ErrorCode::JsonGeneratorError)?;
+                Ok(OperationOutcome::Rendered(pretty))
+            }
+            "assert" => {
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let epsilon = self.parse_epsilon(&mut pico_args)?;
+                let golden = read_key_value_file(&file)?;
+                // Same diff as "plan", but any difference fails the operation
+                let plan = self.plan_changes(&golden, epsilon)?;
+                let mismatches: Vec<String> = plan
+                    .create
+                    .iter()
+                    .map(|key| format!("missing '{}'", key))
+                    .chain(plan.update.iter().map(|key| format!("differs '{}'", key)))
+                    .chain(
+                        plan.delete
+                            .iter()
+                            .map(|key| format!("unexpected '{}'", key)),
+                    )
+                    .collect();
+                if !mismatches.is_empty() {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::ValidationFailed,
+                        format!("Store does not match {}: {}", file, mismatches.join(", ")),
+                    ));
+                }
+                Ok(OperationOutcome::Done)
+            }
+            "expect" => {
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let epsilon = self.parse_epsilon(&mut pico_args)?;
+                let expected = read_key_value_file(&file)?;
+                // Like "assert", but only the listed keys are checked and extra keys are ignored
+                let plan = self.plan_changes(&expected, epsilon)?;
+                let mismatches = plan
+                    .create
+                    .iter()
+                    .map(|key| format!("missing '{}'", key))
+                    .chain(plan.update.iter().map(|key| format!("differs '{}'", key)))
+                    .collect();
+                Ok(OperationOutcome::Strings(mismatches))
+            }
+            "resolve" => {
+                let key: String = pico_args
+                    .opt_value_from_str(["-k", "--key"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                // Follow {"$ref": "otherkey"} links until a value that is not a link
+                let mut path = vec![key];
+                loop {
+                    let value = self.kvs.get_value_raw(&path[path.len() - 1])?;
+                    let Some(target) = ref_target(&value) else {
+                        return Ok(OperationOutcome::Value(value));
+                    };
+                    let revisits = path.iter().any(|key| key == target);
+                    path.push(target.to_string());
+                    if revisits {
+                        return Err(ToolError::with_detail(
+                            ErrorCode::ValidationFailed,
+                            format!("Reference cycle: {}", path.join(" -> ")),
+                        ));
+                    }
+                }
+            }
+            "sample" => {
+                let count: usize = pico_args
+                    .opt_value_from_str("--count")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let seed: u64 = pico_args
+                    .opt_value_from_str("--seed")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                // Sort first so the sample depends only on the key set, not the listing order
+                let mut keys = self.kvs.get_all_keys()?;
+                keys.sort();
+                seeded_shuffle(&mut keys, seed);
+                keys.truncate(count);
+                let mut sample = HashMap::new();
+                for key in keys {
+                    let value = self.kvs.get_value_raw(&key)?;
+                    sample.insert(key, value);
+                }
+                Ok(OperationOutcome::Values(sample))
+            }
+            "migratefilenames" => {
+                let pad: usize = pico_args
+                    .opt_value_from_str("--pad")
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let dry_run = pico_args.contains("--dry-run");
+                let mut renames = Vec::new();
+                for id in 1..=self.kvs.snapshot_count() as u32 {
+                    let kvs_name = self.kvs.get_kvs_filename(id);
+                    let hash_name = self.kvs.get_hash_filename(id);
+                    let new_kvs_name = pad_snapshot_id(&kvs_name, id, pad);
+                    let new_hash_name = pad_snapshot_id(&hash_name, id, pad);
+                    if !dry_run {
+                        self.kvs
+                            .rename_snapshot_files(id, &new_kvs_name, &new_hash_name)?;
+                    }
+                    renames.push((kvs_name, new_kvs_name));
+                    renames.push((hash_name, new_hash_name));
+                }
+                Ok(OperationOutcome::Renames(renames))
+            }
+            "trimarrays" => {
+                // Only trailing nulls go; interior ones may be meaningful positions
+                let mut trimmed = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    let KvsValue::Array(mut items) = self.kvs.get_value_raw(&key)? else {
+                        continue;
+                    };
+                    let len = items.len();
+                    while matches!(items.last(), Some(KvsValue::Null)) {
+                        items.pop();
+                    }
+                    if items.len() != len {
+                        self.kvs.set_value(&key, KvsValue::Array(items))?;
+                        trimmed.push(key);
+                    }
+                }
+                trimmed.sort();
+                Ok(OperationOutcome::keys(trimmed))
+            }
+            "compact" => {
+                let mut changed = 0;
+                for key in self.kvs.get_all_keys()? {
+                    let value = self.kvs.get_value_raw(&key)?;
+                    let canonical = normalize(&value);
+                    // Bitwise comparison, as kvs_eq would treat -0.0 and 0.0 as equal
+                    if !normalized_eq(&value, &canonical, 0.0) {
+                        self.kvs.set_value(&key, canonical)?;
+                        changed += 1;
+                    }
+                }
+                Ok(OperationOutcome::Count(changed))
+            }
+            "lint" => {
+                let mut malformed = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    if !round_trips(&self.kvs.get_value_string(&key)?) {
+                        malformed.push(key);
+                    }
+                }
+                malformed.sort();
+                Ok(OperationOutcome::keys(malformed))
+            }
+            "countvalue" => {
+                let payload: String = pico_args
+                    .opt_value_from_str(["-p", "--payload"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let target = parse_payload(payload)?;
+                let mut count = 0;
+                for key in self.kvs.get_all_keys()? {
+                    if kvs_eq(&self.kvs.get_value_raw(&key)?, &target) {
+                        count += 1;
+                    }
+                }
+                Ok(OperationOutcome::Count(count))
+            }
+            "changedsince" => {
+                let snapshot_id = parse_snapshot_id(&mut pico_args)?;
+                let snapshot = self.read_snapshot(snapshot_id)?;
+                // Keys added since the snapshot count as changed; removed keys are not reported
+                let changed = self
+                    .read_all()?
+                    .into_iter()
+                    .filter(|(key, value)| !snapshot.get(key).is_some_and(|old| kvs_eq(old, value)))
+                    .collect();
+                Ok(OperationOutcome::Values(changed))
+            }
+            "casecollisions" => {
+                // Keys that a case-insensitive store would treat as one
+                let mut by_lowercase: HashMap<String, Vec<String>> = HashMap::new();
+                for key in self.kvs.get_all_keys()? {
+                    by_lowercase
+                        .entry(key.to_lowercase())
+                        .or_default()
+                        .push(key);
+                }
+                let mut collisions: Vec<Vec<String>> = by_lowercase
+                    .into_values()
+                    .filter_map(|mut keys| {
+                        keys.sort();
+                        keys.dedup();
+                        (keys.len() > 1).then_some(keys)
+                    })
+                    .collect();
+                collisions.sort();
+                Ok(OperationOutcome::Groups(collisions))
+            }
+            "capabilities" => Ok(OperationOutcome::Capabilities(vec![
+                ("snapshots", self.kvs.supports_snapshots()),
+                ("raw_reads", self.kvs.supports_raw_reads()),
+                ("concurrent_reads", self.kvs.thread_safe().is_some()),
+                // dumpall streams with any backend
+                ("streaming", true),
+            ])),
+            "selftest" => {
+                let probe_only = pico_args.contains("--probe-only");
+                if probe_only {
+                    // Reads only, for stores that must not be written to
+                    let key: Option<String> = pico_args
+                        .opt_value_from_str(["-k", "--key"])
+                        .map_err(|_| ErrorCode::UnmappedError)?;
+                    match key {
+                        Some(key) => {
+                            self.kvs.get_value_raw(&key)?;
+                        }
+                        None => {
+                            self.kvs.snapshot_count();
+                        }
+                    }
+                    return Ok(OperationOutcome::Done);
+                }
+                let written = KvsValue::String("selftest".to_string());
+                self.kvs.set_value(SELFTEST_KEY, written.clone())?;
+                let read = self.kvs.get_value_raw(SELFTEST_KEY);
+                self.kvs.remove_key(SELFTEST_KEY)?;
+                if !kvs_eq(&read?, &written) {
+                    return Err(ToolError::with_detail(
+                        ErrorCode::ValidationFailed,
+                        "Self-test read back a different value than it wrote",
+                    ));
+                }
+                Ok(OperationOutcome::Done)
+            }
+            "driftfromdefault" => {
+                let mut diffs = Vec::new();
+                for key in self.kvs.get_all_keys()? {
+                    // Keys without a default have nothing to drift from
+                    let default = match self.kvs.get_default_value(&key) {
+                        Ok(default) => default,
+                        Err(ErrorCode::KeyNotFound) => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    let current = self.kvs.get_value_raw(&key)?;
+                    if !kvs_eq(&default, &current) {
+                        diffs.push((key, render_diff(&default, &current)));
+                    }
+                }
+                diffs.sort();
+                Ok(OperationOutcome::Diffs(diffs))
+            }
+            "typedrift" => {
+                let first = parse_snapshot_id(&mut pico_args)?;
+                let second =
+                    parse_snapshot_id_option(&mut pico_args, "--snapshotid2", "--snapshotid2")?;
+                let (before, after) = (self.read_snapshot(first)?, self.read_snapshot(second)?);
+                // Keys present in only one of the snapshots are not reported
+                let mut drifted: Vec<_> = before
+                    .iter()
+                    .filter_map(|(key, old)| {
+                        let new = after.get(key)?;
+                        let (old_type, new_type) = (kvs_type_name(old), kvs_type_name(new));
+                        (old_type != new_type).then(|| (key.clone(), old_type, new_type))
+                    })
+                    .collect();
+                drifted.sort();
+                Ok(OperationOutcome::TypeDrift(drifted))
+            }
+            "exportdiff" => {
+                let first = parse_snapshot_id(&mut pico_args)?;
+                let second =
+                    parse_snapshot_id_option(&mut pico_args, "--snapshotid2", "--snapshotid2")?;
+                let file: String = pico_args
+                    .opt_value_from_str(["-f", "--file"])
+                    .map_err(|_| ErrorCode::UnmappedError)?
+                    .ok_or(ErrorCode::UnmappedError)?;
+                let (before, after) = (self.read_snapshot(first)?, self.read_snapshot(second)?);
+                // One RFC 6902 patch over the whole store, each key a top-level member, which
+                // "patch" without -k replays
+                let mut ops = Vec::new();
+                diff_patch_ops(
+                    "",
+                    &KvsValue::Object(before),
+                    &KvsValue::Object(after),
+                    &mut ops,
+                )?;
+                write_output_file(&file, &format!("[{}]", ops.join(",")))?;
+                Ok(OperationOutcome::Count(ops.len()))
+            }
+            _ => Err(ErrorCode::UnmappedError.into()),
+        }
+    }
+}
+
+// --fail-on-empty: an empty store is an error rather than an empty result
+fn check_not_empty(fail_on_empty: bool, empty: bool) -> Result<(), ToolError> {
+    match fail_on_empty && empty {
+        true => Err(ToolError::with_detail(
+            ErrorCode::KeyNotFound,
+            "Store has no keys (--fail-on-empty)",
+        )),
+        false => Ok(()),
+    }
+}
+
+// Parses -s/--snapshotid, accepting only non-negative integers
+fn parse_snapshot_id(pico_args: &mut pico_args::Arguments) -> Result<u32, ToolError> {
+    parse_snapshot_id_option(pico_args, ["-s", "--snapshotid"], "-s")
+}
+
+// Parses the snapshot id given under `keys`; `flag` names the option in error details
+fn parse_snapshot_id_option(
+    pico_args: &mut pico_args::Arguments,
+    keys: impl Into<pico_args::Keys>,
+    flag: &str,
+) -> Result<u32, ToolError> {
+    let id: String = pico_args
+        .opt_value_from_str(keys)
+        .map_err(|_| ErrorCode::UnmappedError)?
+        .ok_or_else(|| {
+            ToolError::with_detail(
+                ErrorCode::InvalidSnapshotId,
+                format!("Missing snapshot id ({} <id>)", flag),
+            )
+        })?;
+    // u32::from_str alone would also accept a leading '+'
+    let parsed = match id.bytes().all(|b| b.is_ascii_digit()) {
+        true => id.parse().ok(),
+        false => None,
+    };
+    parsed.ok_or_else(|| {
+        ToolError::with_detail(
+            ErrorCode::InvalidSnapshotId,
+            format!(
+                "Invalid snapshot id '{}': expected a non-negative integer up to {}",
+                id,
+                u32::MAX
+            ),
+        )
+    })
+}
+
+// Parses a -p/--payload argument: JSON when it parses, otherwise the text as a string
+fn parse_payload(payload: String) -> Result<KvsValue, ToolError> {
+    match payload.parse::<JsonValue>() {
+        Ok(json) => Ok(convert_json_to_kvs(&json)),
+        // Text shaped like an object or array was meant as JSON, so point at the typo
+        // instead of silently storing it as a string
+        Err(e) if payload.trim_start().starts_with(['{', '[']) => Err(ToolError::with_detail(
+            ErrorCode::JsonParserError,
+            format!("Invalid JSON payload: {}", e),
+        )),
+        Err(_) => Ok(KvsValue::String(payload)),
+    }
+}
+
+// Renames the field at a dot-separated path (e.g. "db.oldname") within nested objects.
+// Objects are unordered, so the renamed field keeps only its value, not its position.
+fn rename_field(value: &mut KvsValue, path: &str, new_name: &str) -> Result<(), ToolError> {
+    let (parents, field) = match path.rsplit_once('.') {
+        Some((parents, field)) => (parents.split('.').collect(), field),
+        None => (Vec::new(), path),
+    };
+    let mut current = value;
+    for (depth, segment) in parents.iter().enumerate() {
+        let KvsValue::Object(obj) = current else {
+            return Err(ToolError::with_detail(
+                ErrorCode::ValidationFailed,
+                format!("'{}' is not an object", parents[..depth].join(".")),
+            ));
+        };
+        current = obj.get_mut(*segment).ok_or_else(|| {
+            ToolError::with_detail(
+                ErrorCode::KeyNotFound,
+                format!("no field '{}'", parents[..=depth].join(".")),
+            )
+        })?;
+    }
+    let KvsValue::Object(obj) = current else {
+        return Err(ToolError::with_detail(
+            ErrorCode::ValidationFailed,
+            format!("'{}' is not an object", parents.join(".")),
+        ));
+    };
+    if obj.contains_key(new_name) {
+        return Err(ToolError::with_detail(
+            ErrorCode::ValidationFailed,
+            format!("field '{}' already exists next to '{}'", new_name, path),
+        ));
+    }
+    let moved = obj.remove(field).ok_or_else(|| {
+        ToolError::with_detail(ErrorCode::KeyNotFound, format!("no field '{}'", path))
+    })?;
+    obj.insert(new_name.to_string(), moved);
+    Ok(())
+}
+
+// Lines describing how the store changed between two polls, in key order:
+// "added k: v", "removed k" and "changed k: old -> new"
+fn store_changes(
+    previous: &HashMap<String, KvsValue>,
+    current: &HashMap<String, KvsValue>,
+) -> Result<Vec<String>, ErrorCode> {
+    let mut keys: Vec<&String> = previous.keys().chain(current.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut lines = Vec::new();
+    for key in keys {
+        match (previous.get(key), current.get(key)) {
+            (None, Some(new)) => {
+                lines.push(format!("added {}: {}", key, render_json_value(new, true)?))
+            }
+            (Some(_), None) => lines.push(format!("removed {}", key)),
+            (Some(old), Some(new)) if !kvs_eq(old, new) => lines.push(format!(
+                "changed {}: {} -> {}",
+                key,
+                render_json_value(old, true)?,
+                render_json_value(new, true)?
+            )),
+            _ => {}
+        }
+    }
+    Ok(lines)
+}
+
+// Parses --redact, a comma-separated list of key names whose values exports must not reveal
+fn parse_redact(pico_args: &mut pico_args::Arguments) -> Result<HashSet<String>, ToolError> {
+    let names: Option<String> = pico_args
+        .opt_value_from_str("--redact")
+        .map_err(|_| ErrorCode::UnmappedError)?;
+    Ok(names
+        .iter()
+        .flat_map(|names| names.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Replaces the value of a store key, or of an object member at any depth below it, with
+// "***" when its name is in `names`
+fn redact_entry(key: &str, value: &mut KvsValue, names: &HashSet<String>) {
+    if names.contains(key) {
+        *value = KvsValue::String("***".to_string());
+        return;
+    }
+    match value {
+        KvsValue::Object(obj) => {
+            for (member, value) in obj.iter_mut() {
+                redact_entry(member, value, names);
+            }
+        }
+        // Array items have no name, and parse_redact never yields an empty one
+        KvsValue::Array(arr) => {
+            for item in arr {
+                redact_entry("", item, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Zero-pads the last occurrence of the snapshot id in a filename, e.g. kvs_0_1.json -> kvs_0_001.json
+fn pad_snapshot_id(name: &str, id: u32, pad: usize) -> String {
+    let id = id.to_string();
+    match name.rfind(&id) {
+        Some(pos) => format!("{}{:0>pad$}{}", &name[..pos], id, &name[pos + id.len()..]),
+        None => name.to_string(),
+    }
+}
+
+// Whether a stored representation parses back as JSON. A string value that itself looks like
+// an object or array must parse too, otherwise it is most likely a truncated JSON payload.
+fn round_trips(stored: &str) -> bool {
+    match stored.parse::<JsonValue>() {
+        Ok(JsonValue::String(s)) if s.trim_start().starts_with(['{', '[']) => {
+            s.parse::<JsonValue>().is_ok()
+        }
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+// Convert tinyjson::JsonValue to KvsValue
+fn convert_json_to_kvs(json: &JsonValue) -> KvsValue {
+    match json {
+        JsonValue::Number(n) => KvsValue::Number(*n),
+        JsonValue::Boolean(b) => KvsValue::Boolean(*b),
+        JsonValue::String(s) => KvsValue::String(s.clone()),
+        JsonValue::Null => KvsValue::Null,
+        JsonValue::Array(arr) => KvsValue::Array(arr.iter().map(convert_json_to_kvs).collect()),
+        JsonValue::Object(obj) => KvsValue::Object(
+            obj.iter()
+                .map(|(k, v)| (k.clone(), convert_json_to_kvs(v)))
+                .collect(),
+        ),
+    }
+}
+
+// Interactive session: one command per line without the leading "-o", e.g. "getkey -k MyKey".
+// Arguments are split on whitespace, so payloads cannot contain spaces. Reads within a session
+// are served from a CachingKvs, which any mutating command invalidates.
+struct Repl {
+    wrapper: KvsToolWrapper,
+}
+
+impl Repl {
+    fn new(kvs: Box<dyn KvsTrait>) -> Self {
+        Repl {
+            wrapper: KvsToolWrapper::new(Box::new(CachingKvs::new(kvs))),
+        }
+    }
+
+    // Runs each input line until "exit"/"quit" or end of input, returning one result per command
+    fn run(&self, input: impl BufRead) -> Vec<Result<OperationOutcome, ToolError>> {
+        let mut results = Vec::new();
+        for line in input.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let mut words = line.split_whitespace();
+            let Some(operation) = words.next() else {
+                continue;
+            };
+            if operation == "exit" || operation == "quit" {
+                break;
+            }
+            let mut args = vec!["-o", operation];
+            args.extend(words);
+            results.push(self.wrapper.execute_operation(args));
+        }
+        results
+    }
+}
+
+// Convert KvsValue to tinyjson::JsonValue
+fn convert_kvs_to_json(value: &KvsValue) -> JsonValue {
+    match value {
+        KvsValue::Number(n) => JsonValue::Number(*n),
+        KvsValue::Boolean(b) => JsonValue::Boolean(*b),
+        KvsValue::String(s) => JsonValue::String(s.clone()),
+        KvsValue::Null => JsonValue::Null,
+        KvsValue::Array(arr) => JsonValue::Array(arr.iter().map(convert_kvs_to_json).collect()),
+        KvsValue::Object(obj) => JsonValue::Object(
+            obj.iter()
+                .map(|(k, v)| (k.clone(), convert_kvs_to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+// Renders a key/value map as a JSON object with its keys sorted.
+// With sort_keys, objects nested in the values are sorted too, at any depth.
+fn render_json(values: &HashMap<String, KvsValue>, sort_keys: bool) -> Result<String, ErrorCode> {
+    let mut keys: Vec<&String> = values.keys().collect();
+    keys.sort();
+    let mut members = Vec::with_capacity(keys.len());
+    for key in keys {
+        let key_json = JsonValue::String(key.clone())
+            .stringify()
+            .map_err(|_| ErrorCode::JsonGeneratorError)?;
+        let value_json = render_json_value(&values[key], sort_keys)?;
+        members.push(format!("{}:{}", key_json, value_json));
+    }
+    Ok(format!("{{{}}}", members.join(",")))
+}
+
+fn render_json_value(value: &KvsValue, sort_keys: bool) -> Result<String, ErrorCode> {
+    match value {
+        KvsValue::Object(obj) if sort_keys => render_json(obj, true),
+        KvsValue::Array(arr) if sort_keys => {
+            let elements = arr
+                .iter()
+                .map(|v| render_json_value(v, true))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("[{}]", elements.join(",")))
+        }
+        _ => convert_kvs_to_json(value)
+            .stringify()
+            .map_err(|_| ErrorCode::JsonGeneratorError),
+    }
+}
+
+// Field order of a JSON payload, kept for --preserve-order. KvsValue objects are unordered
+// and so is the backend's storage, so this is best effort: the order is remembered by the
+// wrapper that wrote the value, lasts only as long as that wrapper, and is applied to the
+// value as it is read back, with any fields it does not know of appended in sorted order.
+#[derive(Clone, Debug)]
+enum FieldOrder {
+    Leaf,
+    Object(Vec<(String, FieldOrder)>),
+    Array(Vec<FieldOrder>),
+}
+
+// Field order of a JSON object or array text; None for scalars and unparsable text
+fn json_field_order(text: &str) -> Option<FieldOrder> {
+    let mut rest = text;
+    match scan_field_order(&mut rest)? {
+        FieldOrder::Leaf => None,
+        order => Some(order),
+    }
+}
+
+fn scan_field_order(rest: &mut &str) -> Option<FieldOrder> {
+    *rest = rest.trim_start();
+    if let Some(body) = rest.strip_prefix('{') {
+        *rest = body;
+        let mut fields = Vec::new();
+        loop {
+            *rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('}') {
+                *rest = after;
+                return Some(FieldOrder::Object(fields));
+            }
+            let name = scan_json_string(rest)?;
+            *rest = rest.trim_start().strip_prefix(':')?;
+            fields.push((name, scan_field_order(rest)?));
+            *rest = rest.trim_start();
+            *rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+    if let Some(body) = rest.strip_prefix('[') {
+        *rest = body;
+        let mut items = Vec::new();
+        loop {
+            *rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                *rest = after;
+                return Some(FieldOrder::Array(items));
+            }
+            items.push(scan_field_order(rest)?);
+            *rest = rest.trim_start();
+            *rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+    if rest.starts_with('"') {
+        scan_json_string(rest)?;
+        return Some(FieldOrder::Leaf);
+    }
+    // Number, boolean or null
+    let end = rest
+        .find(|c: char| matches!(c, ',' | '}' | ']') || c.is_whitespace())
+        .unwrap_or(rest.len());
+    if end == 0 {
+        return None;
+    }
+    *rest = &rest[end..];
+    Some(FieldOrder::Leaf)
+}
+
+// Consumes a JSON string literal and returns its unescaped text
+fn scan_json_string(rest: &mut &str) -> Option<String> {
+    let body = rest.strip_prefix('"')?;
+    let mut escaped = false;
+    let (end, _) = body.char_indices().find(|&(_, c)| {
+        let closes = c == '"' && !escaped;
+        escaped = c == '\\' && !escaped;
+        closes
+    })?;
+    let literal = &rest[..end + 2];
+    *rest = &body[end + 1..];
+    match literal.parse::<JsonValue>().ok()? {
+        JsonValue::String(text) => Some(text),
+        _ => None,
+    }
+}
+
+// Renders a value with its objects' fields in the recorded order
+fn render_json_ordered(value: &KvsValue, order: &FieldOrder) -> Result<String, ErrorCode> {
+    let member =
+        |name: &String, value: &KvsValue, order: &FieldOrder| -> Result<String, ErrorCode> {
+            let name_json = JsonValue::String(name.clone())
+                .stringify()
+                .map_err(|_| ErrorCode::JsonGeneratorError)?;
+            Ok(format!(
+                "{}:{}",
+                name_json,
+                render_json_ordered(value, order)?
+            ))
+        };
+    match (value, order) {
+        (KvsValue::Object(obj), FieldOrder::Object(fields)) => {
+            let mut members = Vec::with_capacity(obj.len());
+            let mut written = HashSet::new();
+            for (name, child) in fields {
+                if let Some(value) = obj.get(name) {
+                    if written.insert(name) {
+                        members.push(member(name, value, child)?);
+                    }
+                }
+            }
+            let mut unknown: Vec<&String> =
+                obj.keys().filter(|name| !written.contains(name)).collect();
+            unknown.sort();
+            for name in unknown {
+                members.push(member(name, &obj[name], &FieldOrder::Leaf)?);
+            }
+            Ok(format!("{{{}}}", members.join(",")))
+        }
+        (KvsValue::Array(arr), FieldOrder::Array(items)) => {
+            let elements = arr
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    render_json_ordered(value, items.get(i).unwrap_or(&FieldOrder::Leaf))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("[{}]", elements.join(",")))
+        }
+        _ => render_json_value(value, false),
+    }
+}
+
+// Renders a key/value map as block-style YAML with sorted keys. Only emits; there is no parser.
+fn render_yaml(values: &HashMap<String, KvsValue>) -> String {
+    let mut lines = Vec::new();
+    yaml_map_lines(values, &mut lines);
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn yaml_map_lines(map: &HashMap<String, KvsValue>, lines: &mut Vec<String>) {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for key in keys {
+        let key_text = yaml_string(key);
+        match yaml_block_lines(&map[key]) {
+            Some(nested) => {
+                lines.push(format!("{}:", key_text));
+                lines.extend(nested.into_iter().map(|line| format!("  {}", line)));
+            }
+            None => lines.push(format!("{}: {}", key_text, yaml_scalar(&map[key]))),
+        }
+    }
+}
+
+// Lines of a non-empty container, or None for values written inline
+fn yaml_block_lines(value: &KvsValue) -> Option<Vec<String>> {
+    let mut lines = Vec::new();
+    match value {
+        KvsValue::Object(obj) if !obj.is_empty() => yaml_map_lines(obj, &mut lines),
+        KvsValue::Array(arr) if !arr.is_empty() => {
+            for item in arr {
+                match yaml_block_lines(item) {
+                    Some(nested) => {
+                        for (i, line) in nested.into_iter().enumerate() {
+                            let prefix = if i == 0 { "- " } else { "  " };
+                            lines.push(format!("{}{}", prefix, line));
+                        }
+                    }
+                    None => lines.push(format!("- {}", yaml_scalar(item))),
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(lines)
+}
+
+fn yaml_scalar(value: &KvsValue) -> String {
+    match value {
+        KvsValue::Null => "null".to_string(),
+        KvsValue::Boolean(b) => b.to_string(),
+        KvsValue::Number(n) if n.is_nan() => ".nan".to_string(),
+        KvsValue::Number(n) if n.is_infinite() => {
+            if *n > 0.0 { ".inf" } else { "-.inf" }.to_string()
+        }
+        KvsValue::Number(n) => n.to_string(),
+        KvsValue::String(s) => yaml_string(s),
+        KvsValue::Array(_) => "[]".to_string(),
+        KvsValue::Object(_) => "{}".to_string(),
+    }
+}
+
+// Plain scalar when unambiguous, otherwise a double-quoted string
+fn yaml_string(s: &str) -> String {
+    let reserved = ["null", "~", "true", "false", "yes", "no", "on", "off"];
+    let needs_quotes = s.is_empty()
+        || s.starts_with(|c: char| c.is_whitespace() || "-?:,[]{}#&*!|>'\"%@`".contains(c))
+        || s.ends_with(char::is_whitespace)
+        || s.contains(':')
+        || s.contains(" #")
+        || s.chars().any(char::is_control)
+        || reserved.contains(&s.to_lowercase().as_str())
+        || s.parse::<f64>().is_ok();
+    if !needs_quotes {
+        return s.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Character encoding of an input file, from --input-encoding
+#[derive(Clone, Copy)]
+enum InputEncoding {
+    Utf8,
+    Latin1,
+}
+
+impl InputEncoding {
+    fn parse(pico_args: &mut pico_args::Arguments) -> Result<Self, ToolError> {
+        let name: Option<String> = pico_args
+            .opt_value_from_str("--input-encoding")
+            .map_err(|_| ErrorCode::UnmappedError)?;
+        match name.as_deref() {
+            None | Some("utf8") => Ok(InputEncoding::Utf8),
+            Some("latin1") => Ok(InputEncoding::Latin1),
+            Some(other) => Err(ToolError::with_detail(
+                ErrorCode::UnmappedError,
+                format!(
+                    "Unsupported --input-encoding '{}' (use utf8 or latin1)",
+                    other
+                ),
+            )),
+        }
+    }
+
+    fn decode(self, path: &str, bytes: Vec<u8>) -> Result<String, ToolError> {
+        match self {
+            InputEncoding::Utf8 => String::from_utf8(bytes).map_err(|e| {
+                ToolError::with_detail(
+                    ErrorCode::ConversionFailed,
+                    format!(
+                        "{}: invalid UTF-8 at byte {}; use --input-encoding latin1 for legacy files",
+                        path,
+                        e.utf8_error().valid_up_to()
+                    ),
+                )
+            }),
+            // Every byte is a character: Latin-1 is the first 256 code points
+            InputEncoding::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+        }
+    }
+}
+
+fn read_json_file(path: &str) -> Result<JsonValue, ToolError> {
+    read_json_file_as(path, InputEncoding::Utf8)
+}
+
+fn read_json_file_as(path: &str, encoding: InputEncoding) -> Result<JsonValue, ToolError> {
+    let bytes = fs::read(path)
+        .map_err(|e| ToolError::with_detail(ErrorCode::FileNotFound, format!("{}: {}", path, e)))?;
+    encoding
+        .decode(path, bytes)?
+        .parse::<JsonValue>()
+        .map_err(|e| ToolError::with_detail(ErrorCode::JsonParserError, format!("{}: {}", path, e)))
+}
+
+// Reads a JSON file holding a key/value object, e.g. a desired state
+fn read_key_value_file(path: &str) -> Result<HashMap<String, KvsValue>, ToolError> {
+    key_value_object(path, &read_json_file(path)?)
+}
+
+fn key_value_object(path: &str, json: &JsonValue) -> Result<HashMap<String, KvsValue>, ToolError> {
+    match convert_json_to_kvs(json) {
+        KvsValue::Object(map) => Ok(map),
+        _ => Err(ToolError::with_detail(
+            ErrorCode::JsonParserError,
+            format!("{}: expected a JSON object of key/value pairs", path),
+        )),
+    }
+}
+
+// Reads an import source: either a key/value object, imported in sorted key order, or an
+// array of [key, value] pairs, imported in file order and allowed to repeat a key
+fn read_import_entries(
+    path: &str,
+    encoding: InputEncoding,
+) -> Result<Vec<(String, KvsValue)>, ToolError> {
+    match read_json_file_as(path, encoding)? {
+        JsonValue::Object(obj) => {
+            let mut entries: Vec<(String, KvsValue)> = obj
+                .iter()
+                .map(|(key, value)| (key.clone(), convert_json_to_kvs(value)))
+                .collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(entries)
+        }
+        JsonValue::Array(pairs) => pairs
+            .iter()
+            .map(|pair| match pair {
+                JsonValue::Array(pair) if pair.len() == 2 => match &pair[0] {
+                    JsonValue::String(key) => Ok((key.clone(), convert_json_to_kvs(&pair[1]))),
+                    _ => Err(()),
+                },
+                _ => Err(()),
+            })
+            .collect::<Result<_, ()>>()
+            .map_err(|_| {
+                ToolError::with_detail(
+                    ErrorCode::JsonParserError,
+                    format!("{}: expected an array of [key, value] pairs", path),
+                )
+            }),
+        _ => Err(ToolError::with_detail(
+            ErrorCode::JsonParserError,
+            format!(
+                "{}: expected a JSON object or an array of [key, value] pairs",
+                path
+            ),
+        )),
+    }
+}
+
+// Combines key/value files for import --merge in sorted key order. Object values of a key
+// found in several files are deep-merged; on any other conflict the later file wins.
+fn read_merged_entries(
+    paths: &[String],
+    encoding: InputEncoding,
+) -> Result<Vec<(String, KvsValue)>, ToolError> {
+    let mut merged: HashMap<String, KvsValue> = HashMap::new();
+    for path in paths {
+        for (key, value) in key_value_object(path, &read_json_file_as(path, encoding)?)? {
+            let value = match merged.remove(&key) {
+                Some(earlier) => deep_merge(earlier, value),
+                None => value,
+            };
+            merged.insert(key, value);
+        }
+    }
+    let mut entries: Vec<(String, KvsValue)> = merged.into_iter().collect();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(entries)
+}
+
+fn deep_merge(base: KvsValue, overlay: KvsValue) -> KvsValue {
+    match (base, overlay) {
+        (KvsValue::Object(mut base), KvsValue::Object(overlay)) => {
+            for (key, value) in overlay {
+                let value = match base.remove(&key) {
+                    Some(earlier) => deep_merge(earlier, value),
+                    None => value,
+                };
+                base.insert(key, value);
+            }
+            KvsValue::Object(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+// RFC 6902 operations turning `old` into `new`, each rendered as a JSON object. Objects are
+// compared field by field and arrays index by index, with surplus items removed from the end
+// and missing ones appended; anything else that differs is replaced as a whole.
+fn diff_patch_ops(
+    path: &str,
+    old: &KvsValue,
+    new: &KvsValue,
+    ops: &mut Vec<String>,
+) -> Result<(), ErrorCode> {
+    let op = |name: &str, path: &str, value: Option<&KvsValue>| -> Result<String, ErrorCode> {
+        let path_json = JsonValue::String(path.to_string())
+            .stringify()
+            .map_err(|_| ErrorCode::JsonGeneratorError)?;
+        Ok(match value {
+            Some(value) => format!(
+                r#"{{"op":"{}","path":{},"value":{}}}"#,
+                name,
+                path_json,
+                render_json_value(value, true)?
+            ),
+            None => format!(r#"{{"op":"{}","path":{}}}"#, name, path_json),
+        })
+    };
+    match (old, new) {
+        (KvsValue::Object(a), KvsValue::Object(b)) => {
+            let mut names: Vec<&String> = a.keys().chain(b.keys()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let child = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+                match (a.get(name), b.get(name)) {
+                    (Some(old), Some(new)) => diff_patch_ops(&child, old, new, ops)?,
+                    (Some(_), None) => ops.push(op("remove", &child, None)?),
+                    (None, Some(new)) => ops.push(op("add", &child, Some(new))?),
+                    (None, None) => {}
+                }
+            }
+        }
+        (KvsValue::Array(a), KvsValue::Array(b)) => {
+            for (i, (old, new)) in a.iter().zip(b).enumerate() {
+                diff_patch_ops(&format!("{}/{}", path, i), old, new, ops)?;
+            }
+            // Highest index first, so earlier removals do not shift later ones
+            for i in (b.len()..a.len()).rev() {
+                ops.push(op("remove", &format!("{}/{}", path, i), None)?);
+            }
+            for new in b.iter().skip(a.len()) {
+                ops.push(op("add", &format!("{}/-", path), Some(new))?);
+            }
+        }
+        _ if !kvs_eq(old, new) => ops.push(op("replace", path, Some(new))?),
+        _ => {}
+    }
+    Ok(())
+}
+
+// Applies one RFC 6902 operation (add, remove or replace) to a value
+fn apply_patch_op(doc: &mut KvsValue, op: &JsonValue) -> Result<(), ToolError> {
+    let invalid = |detail: String| ToolError::with_detail(ErrorCode::ValidationFailed, detail);
+    let field = |name: &str| match op {
+        JsonValue::Object(obj) => obj.get(name),
+        _ => None,
+    };
+    let Some(JsonValue::String(name)) = field("op") else {
+        return Err(invalid("Patch operation without \"op\"".to_string()));
+    };
+    let Some(JsonValue::String(path)) = field("path") else {
+        return Err(invalid(format!(
+            "Patch operation '{}' without \"path\"",
+            name
+        )));
+    };
+    let value = match name.as_str() {
+        "add" | "replace" => Some(convert_json_to_kvs(field("value").ok_or_else(|| {
+            invalid(format!("Patch operation '{}' without \"value\"", name))
+        })?)),
+        "remove" => None,
+        _ => return Err(invalid(format!("Unsupported patch operation '{}'", name))),
+    };
+    let unresolved = || invalid(format!("Path '{}' does not resolve", path));
+
+    // JSON pointer: "/a/0/b", with "~1" for '/' and "~0" for '~' inside a token
+    let tokens: Vec<String> = match path.strip_prefix('/') {
+        Some(rest) => rest
+            .split('/')
+            .map(|t| t.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None if path.is_empty() => Vec::new(),
+        None => return Err(unresolved()),
+    };
+    let Some((last, parents)) = tokens.split_last() else {
+        // The empty path addresses the whole value
+        return match value {
+            Some(v) => {
+                *doc = v;
+                Ok(())
+            }
+            None => Err(unresolved()),
+        };
+    };
+    let mut target = doc;
+    for token in parents {
+        target = match target {
+            KvsValue::Object(obj) => obj.get_mut(token),
+            KvsValue::Array(arr) => token.parse::<usize>().ok().and_then(|i| arr.get_mut(i)),
+            _ => None,
+        }
+        .ok_or_else(unresolved)?;
+    }
+    match (target, value) {
+        (KvsValue::Object(obj), Some(v)) if name == "add" || obj.contains_key(last) => {
+            obj.insert(last.clone(), v);
+        }
+        (KvsValue::Object(obj), None) if obj.contains_key(last) => {
+            obj.remove(last);
+        }
+        (KvsValue::Array(arr), Some(v)) if name == "add" && last == "-" => arr.push(v),
+        (KvsValue::Array(arr), value) => {
+            let index = last.parse::<usize>().map_err(|_| unresolved())?;
+            match value {
+                Some(v) if name == "add" && index <= arr.len() => arr.insert(index, v),
+                Some(v) if index < arr.len() => arr[index] = v,
+                None if index < arr.len() => {
+                    arr.remove(index);
+                }
+                _ => return Err(unresolved()),
+            }
+        }
+        _ => return Err(unresolved()),
+    }
+    Ok(())
+}
+
+// Filter for "-o query --where", e.g. `type=number && value>100`.
+// Grammar: or := and ("||" and)*, and := atom ("&&" atom)*, atom := "(" or ")" | field op literal,
+// where field is "type" or "value" and op one of = != < <= > >=.
+#[derive(Debug)]
+enum Predicate {
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Type {
+        name: String,
+        negate: bool,
+    },
+    Value {
+        op: CompareOp,
+        literal: QueryLiteral,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+enum QueryLiteral {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+}
+
+impl Predicate {
+    fn parse(input: &str) -> Result<Predicate, ToolError> {
+        let tokens = tokenize_query(input)?;
+        let mut pos = 0;
+        let predicate = parse_query_or(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            None => Ok(predicate),
+            Some(token) => Err(query_error(format!("unexpected '{}'", token))),
+        }
+    }
+
+    fn matches(&self, value: &KvsValue) -> bool {
+        match self {
+            Predicate::And(a, b) => a.matches(value) && b.matches(value),
+            Predicate::Or(a, b) => a.matches(value) || b.matches(value),
+            Predicate::Type { name, negate } => (kvs_type_name(value) == name) != *negate,
+            Predicate::Value { op, literal } => {
+                let ordering = match (value, literal) {
+                    (KvsValue::Number(n), QueryLiteral::Number(l)) => n.partial_cmp(l),
+                    (KvsValue::Boolean(b), QueryLiteral::Boolean(l)) => Some(b.cmp(l)),
+                    (KvsValue::String(s), QueryLiteral::String(l)) => Some(s.as_str().cmp(l)),
+                    _ => None,
+                };
+                // Values of another type than the literal only satisfy "!="
+                let Some(ordering) = ordering else {
+                    return matches!(op, CompareOp::Ne);
+                };
+                match op {
+                    CompareOp::Eq => ordering.is_eq(),
+                    CompareOp::Ne => ordering.is_ne(),
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Le => ordering.is_le(),
+                    CompareOp::Gt => ordering.is_gt(),
+                    CompareOp::Ge => ordering.is_ge(),
+                }
+            }
+        }
+    }
+}
+
+fn query_error(message: String) -> ToolError {
+    ToolError::with_detail(
+        ErrorCode::ValidationFailed,
+        format!("Invalid query: {}", message),
+    )
+}
+
+// Splits a query into parentheses, operators, bare words and quoted strings.
+// Quoted strings keep their opening quote so they are never read as numbers.
+fn tokenize_query(input: &str) -> Result<Vec<String>, ToolError> {
+    const OPERATOR_CHARS: &str = "&|=!<>";
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::from('"');
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => return Err(query_error("unterminated string".to_string())),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let is_operator = OPERATOR_CHARS.contains(c);
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                    break;
+                }
+                if OPERATOR_CHARS.contains(c) != is_operator {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_query_or(tokens: &[String], pos: &mut usize) -> Result<Predicate, ToolError> {
+    let mut predicate = parse_query_and(tokens, pos)?;
+    while tokens.get(*pos).is_some_and(|t| t == "||") {
+        *pos += 1;
+        predicate = Predicate::Or(Box::new(predicate), Box::new(parse_query_and(tokens, pos)?));
+    }
+    Ok(predicate)
+}
+
+fn parse_query_and(tokens: &[String], pos: &mut usize) -> Result<Predicate, ToolError> {
+    let mut predicate = parse_query_atom(tokens, pos)?;
+    while tokens.get(*pos).is_some_and(|t| t == "&&") {
+        *pos += 1;
+        predicate = Predicate::And(
+            Box::new(predicate),
+            Box::new(parse_query_atom(tokens, pos)?),
+        );
+    }
+    Ok(predicate)
+}
+
+fn parse_query_atom(tokens: &[String], pos: &mut usize) -> Result<Predicate, ToolError> {
+    let mut next = || {
+        let token = tokens.get(*pos).cloned();
+        *pos += 1;
+        token.ok_or_else(|| query_error("unexpected end of query".to_string()))
+    };
+    let field = next()?;
+    if field == "(" {
+        let predicate = parse_query_or(tokens, pos)?;
+        if tokens.get(*pos).is_some_and(|t| t == ")") {
+            *pos += 1;
+            return Ok(predicate);
+        }
+        return Err(query_error("missing ')'".to_string()));
+    }
+    let op = match next()?.as_str() {
+        "=" | "==" => CompareOp::Eq,
+        "!=" => CompareOp::Ne,
+        "<" => CompareOp::Lt,
+        "<=" => CompareOp::Le,
+        ">" => CompareOp::Gt,
+        ">=" => CompareOp::Ge,
+        other => return Err(query_error(format!("unknown operator '{}'", other))),
+    };
+    let literal = next()?;
+    match field.as_str() {
+        "type" => match op {
+            CompareOp::Eq | CompareOp::Ne => Ok(Predicate::Type {
+                name: literal.trim_start_matches('"').to_string(),
+                negate: matches!(op, CompareOp::Ne),
+            }),
+            _ => Err(query_error("type only supports = and !=".to_string())),
+        },
+        "value" => {
+            let literal = if let Some(s) = literal.strip_prefix('"') {
+                QueryLiteral::String(s.to_string())
+            } else if let Ok(n) = literal.parse::<f64>() {
+                QueryLiteral::Number(n)
+            } else if let Ok(b) = literal.parse::<bool>() {
+                QueryLiteral::Boolean(b)
+            } else {
+                QueryLiteral::String(literal)
+            };
+            Ok(Predicate::Value { op, literal })
+        }
+        other => Err(query_error(format!("unknown field '{}'", other))),
+    }
+}
+
+// Every kvs_type_name, in the order per-type reports list their categories
+const TYPE_NAMES: [&str; 6] = ["null", "boolean", "number", "string", "array", "object"];
+
+// Name of a value's type as used by queries
+fn kvs_type_name(value: &KvsValue) -> &'static str {
+    match value {
+        KvsValue::Number(_) => "number",
+        KvsValue::Boolean(_) => "boolean",
+        KvsValue::String(_) => "string",
+        KvsValue::Null => "null",
+        KvsValue::Array(_) => "array",
+        KvsValue::Object(_) => "object",
+    }
+}
+
+// Per-type results in TYPE_NAMES order, so reports do not depend on HashMap iteration order.
+// Types without an entry are left out.
+fn in_type_order<T>(mut by_type: HashMap<&'static str, T>) -> Vec<(&'static str, T)> {
+    TYPE_NAMES
+        .iter()
+        .filter_map(|name| by_type.remove_entry(name))
+        .collect()
+}
+
+// Key named by a reference value, an object whose only member is "$ref" with a string
+fn ref_target(value: &KvsValue) -> Option<&str> {
+    match value {
+        KvsValue::Object(obj) if obj.len() == 1 => match obj.get("$ref") {
+            Some(KvsValue::String(target)) => Some(target),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Diffs the desired state against any store using reads only: keys to create, update and delete
+// so that the store matches `desired`
+fn plan_store_changes<T: KvsTrait + ?Sized>(
+    kvs: &T,
+    desired: &HashMap<String, KvsValue>,
+    epsilon: f64,
+) -> Result<ChangePlan, ErrorCode> {
+    let current = kvs.get_all_keys()?;
+    let mut plan = ChangePlan::default();
+    for (key, value) in desired {
+        if !current.contains(key) {
+            plan.create.push(key.clone());
+        } else {
+            let old = kvs.get_value_raw(key)?;
+            if !kvs_eq_within(&old, value, epsilon) {
+                plan.update.push(key.clone());
+                plan.diffs.insert(key.clone(), render_diff(&old, value));
+            }
+        }
+    }
+    plan.delete = current
+        .into_iter()
+        .filter(|key| !desired.contains_key(key))
+        .collect();
+    plan.create.sort();
+    plan.update.sort();
+    plan.delete.sort();
+    Ok(plan)
+}
+
+// Line-oriented diff of two values: "- path: old" and "+ path: new" for every differing field,
+// recursing into objects (path "a.b") and arrays (path "a[0]"). Fields are listed sorted.
+fn render_diff(old: &KvsValue, new: &KvsValue) -> String {
+    let mut lines = Vec::new();
+    diff_lines("", old, new, &mut lines);
+    lines.join("\n")
+}
+
+fn diff_lines(path: &str, old: &KvsValue, new: &KvsValue, lines: &mut Vec<String>) {
+    let mut child =
+        |child_path: String, old: Option<&KvsValue>, new: Option<&KvsValue>| match (old, new) {
+            (Some(old), Some(new)) => diff_lines(&child_path, old, new, lines),
+            (Some(old), None) => lines.push(diff_line('-', &child_path, old)),
+            (None, Some(new)) => lines.push(diff_line('+', &child_path, new)),
+            (None, None) => {}
+        };
+    match (old, new) {
+        (KvsValue::Object(a), KvsValue::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child_path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                child(child_path, a.get(key), b.get(key));
+            }
+        }
+        (KvsValue::Array(a), KvsValue::Array(b)) => {
+            for i in 0..a.len().max(b.len()) {
+                child(format!("{}[{}]", path, i), a.get(i), b.get(i));
+            }
+        }
+        _ if !kvs_eq(old, new) => {
+            lines.push(diff_line('-', path, old));
+            lines.push(diff_line('+', path, new));
+        }
+        _ => {}
+    }
+}
+
+fn diff_line(sign: char, path: &str, value: &KvsValue) -> String {
+    let json = render_json_value(value, true).unwrap_or_else(|_| format!("{:?}", value));
+    match path.is_empty() {
+        true => format!("{} {}", sign, json),
+        false => format!("{} {}: {}", sign, path, json),
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard base64 with '=' padding
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Inverse of base64_encode; None unless the text is padded base64 of the standard alphabet
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, chunk) in text.chunks(4).enumerate() {
+        let last = index + 1 == text.len() / 4;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut group = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let sextet = BASE64_ALPHABET.iter().position(|&a| a == c)?;
+            group = group << 6 | sextet as u32;
+        }
+        group <<= 6 * padding;
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+// SplitMix64 step: advances the state and returns the next pseudo-random number
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Fisher-Yates shuffle driven by SplitMix64, so a seed always yields the same order
+fn seeded_shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    for i in (1..items.len()).rev() {
+        let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+// Test value of a pseudo-random type for createtestdata --count; arrays and objects hold
+// one scalar each so generated data stays small
+fn generated_value(state: &mut u64, index: usize) -> KvsValue {
+    let scalar = |roll: u64| match roll % 4 {
+        0 => KvsValue::Number(index as f64),
+        1 => KvsValue::Boolean(index.is_multiple_of(2)),
+        2 => KvsValue::String(format!("value_{}", index)),
+        _ => KvsValue::Null,
+    };
+    match splitmix64(state) % 6 {
+        4 => KvsValue::Array(vec![scalar(splitmix64(state))]),
+        5 => KvsValue::Object(HashMap::from([(
+            "item".to_string(),
+            scalar(splitmix64(state)),
+        )])),
+        roll => scalar(roll),
+    }
+}
+
+// Nearest-rank percentile of sorted values: the smallest one with at least `p` percent of
+// the values at or below it. 0 for no values.
+fn percentile(sorted: &[usize], p: usize) -> usize {
+    match sorted.len() {
+        0 => 0,
+        len => sorted[(len * p).div_ceil(100).max(1) - 1],
+    }
+}
+
+// Approximate storage footprint of a value in bytes: UTF-8 byte lengths of strings and
+// object keys, and 8 bytes per number
+fn estimated_size(value: &KvsValue) -> usize {
+    match value {
+        KvsValue::Number(_) => 8,
+        KvsValue::Boolean(_) => 1,
+        KvsValue::String(s) => s.len(),
+        KvsValue::Null => 0,
+        KvsValue::Array(arr) => arr.iter().map(estimated_size).sum(),
+        KvsValue::Object(obj) => obj.iter().map(|(k, v)| k.len() + estimated_size(v)).sum(),
+    }
+}
+
+// Reads per-key content hashes written by write_hash_file. A missing file means no hashes yet.
+fn read_hash_file(path: &str) -> Result<HashMap<String, u64>, ToolError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => {
+            return Err(ToolError::with_detail(
+                ErrorCode::FileNotFound,
+                format!("{}: {}", path, e),
+            ))
+        }
+    };
+    let invalid = || {
+        ToolError::with_detail(
+            ErrorCode::JsonParserError,
+            format!("{}: expected a JSON object of hex hashes", path),
+        )
+    };
+    let Ok(JsonValue::Object(obj)) = content.parse::<JsonValue>() else {
+        return Err(invalid());
+    };
+    obj.into_iter()
+        .map(|(key, hash)| match hash {
+            JsonValue::String(hex) => u64::from_str_radix(&hex, 16)
+                .map(|hash| (key, hash))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+// Hashes are stored as hex strings since JSON numbers can't hold every u64
+fn write_hash_file(path: &str, hashes: &HashMap<String, u64>) -> Result<(), ToolError> {
+    let json = JsonValue::Object(
+        hashes
+            .iter()
+            .map(|(key, hash)| (key.clone(), JsonValue::String(format!("{:016x}", hash))))
+            .collect(),
+    );
+    let content = json
+        .stringify()
+        .map_err(|_| ErrorCode::JsonGeneratorError)?;
+    write_output_file(path, &content)
+}
+
+// {"error": "<ErrorCode>", "detail": <detail or null>}
+fn render_error_json(err: &ToolError) -> Result<String, ToolError> {
+    let detail = match &err.detail {
+        Some(detail) => JsonValue::String(detail.clone()),
+        None => JsonValue::Null,
+    };
+    let json = JsonValue::Object(HashMap::from([
+        (
+            "error".to_string(),
+            JsonValue::String(format!("{:?}", err.code)),
+        ),
+        ("detail".to_string(), detail),
+    ]));
+    Ok(json
+        .stringify()
+        .map_err(|_| ErrorCode::JsonGeneratorError)?)
+}
+
+fn write_output_file(path: &str, content: &str) -> Result<(), ToolError> {
+    fs::write(path, content).map_err(|e| {
+        ToolError::with_detail(
+            ErrorCode::PhysicalStorageFailure,
+            format!("{}: {}", path, e),
+        )
+    })
+}
+
+// Canonical form of a value: -0.0 becomes 0.0, every NaN the same NaN, containers recursively.
+// Compare and hash values through this so equal content never depends on number encoding.
+fn normalize(value: &KvsValue) -> KvsValue {
+    match value {
+        KvsValue::Number(n) if *n == 0.0 => KvsValue::Number(0.0),
+        KvsValue::Number(n) if n.is_nan() => KvsValue::Number(f64::NAN),
+        KvsValue::Array(arr) => KvsValue::Array(arr.iter().map(normalize).collect()),
+        KvsValue::Object(obj) => {
+            KvsValue::Object(obj.iter().map(|(k, v)| (k.clone(), normalize(v))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+// Structural equality of two values after normalization. Always exact: the epsilon only
+// applies to the plan diff (plan, apply, assert, expect, reconcile), while --only-if-changed,
+// --verify-after-write, setif, countvalue, changedsince, patch and the diff renderers
+// compare through kvs_eq.
+fn kvs_eq(a: &KvsValue, b: &KvsValue) -> bool {
+    kvs_eq_within(a, b, 0.0)
+}
+
+// Like kvs_eq, with numbers at most `epsilon` apart counting as equal. kvs_hash stays exact,
+// so values equal only within epsilon may hash differently.
+fn kvs_eq_within(a: &KvsValue, b: &KvsValue, epsilon: f64) -> bool {
+    normalized_eq(&normalize(a), &normalize(b), epsilon)
+}
+
+fn normalized_eq(a: &KvsValue, b: &KvsValue, epsilon: f64) -> bool {
+    match (a, b) {
+        (KvsValue::Number(x), KvsValue::Number(y)) => {
+            x.to_bits() == y.to_bits() || (epsilon > 0.0 && (x - y).abs() <= epsilon)
+        }
+        (KvsValue::Boolean(x), KvsValue::Boolean(y)) => x == y,
+        (KvsValue::String(x), KvsValue::String(y)) => x == y,
+        (KvsValue::Null, KvsValue::Null) => true,
+        (KvsValue::Array(x), KvsValue::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| normalized_eq(x, y, epsilon))
+        }
+        (KvsValue::Object(x), KvsValue::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(k, v)| y.get(k).is_some_and(|w| normalized_eq(v, w, epsilon)))
+        }
+        _ => false,
+    }
+}
+
+// Hash consistent with kvs_eq: object members are hashed in sorted key order
+fn kvs_hash(value: &KvsValue) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_normalized(&normalize(value), &mut hasher);
+    hasher.finish()
+}
+
+fn hash_normalized(value: &KvsValue, hasher: &mut DefaultHasher) {
+    match value {
+        KvsValue::Null => 0u8.hash(hasher),
+        KvsValue::Boolean(b) => (1u8, b).hash(hasher),
+        KvsValue::Number(n) => (2u8, n.to_bits()).hash(hasher),
+        KvsValue::String(s) => (3u8, s).hash(hasher),
+        KvsValue::Array(arr) => {
+            (4u8, arr.len()).hash(hasher);
+            arr.iter().for_each(|v| hash_normalized(v, hasher));
+        }
+        KvsValue::Object(obj) => {
+            (5u8, obj.len()).hash(hasher);
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            for key in keys {
+                key.hash(hasher);
+                hash_normalized(&obj[key], hasher);
+            }
+        }
+    }
+}
+
+// Integration tests for transitions
+#[test]
+fn test_getkey_non_existent() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(false));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let result = wrapper.execute_operation(vec!["-o", "getkey", "-k", "MyKey"]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_setkey_getkey_transition() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(true));
+    mock.expect_is_value_default()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("Default".to_string())));
+    mock.expect_get_value_string()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok("Hello World".to_string()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
+        .is_ok());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "MyKey"])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_json_transition() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey"
+                && matches!(
+                    value,
+                    KvsValue::Object(hm) if hm.get("sub-number").map(|v| matches!(v, KvsValue::Number(n) if *n == 789.0)).unwrap_or(false)
+                )
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "MyKey",
+            "-p",
+            r#"{"sub-number":789,"sub-string":"Third"}"#
+        ])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_removekey_transition() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_remove_key()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
+        .is_ok());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "removekey", "-k", "MyKey"])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_listkeys_transition() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "Key1" && matches!(value, KvsValue::String(s) if s == "Value1")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "Key2" && matches!(value, KvsValue::String(s) if s == "Value2")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["Key1".to_string(), "Key2".to_string()]));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Key1", "-p", "Value1"])
+        .is_ok());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Key2", "-p", "Value2"])
+        .is_ok());
+    assert!(wrapper.execute_operation(vec!["-o", "listkeys"]).is_ok());
+}
+
+#[test]
+fn test_setkey_reset_transition() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["MyKey".to_string()]));
+    mock.expect_snapshot_count().times(1).returning(|| 0);
+    mock.expect_reset().times(1).returning(|| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
+        .is_ok());
+    assert!(wrapper.execute_operation(vec!["-o", "reset"]).is_ok());
+}
+
+#[test]
+fn test_snapshotcount() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_supports_snapshots().returning(|| true);
+    mock.expect_snapshot_count().times(1).returning(|| 2);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper.execute_operation(vec!["-o", "snapshotcount"]).is_ok());
+}
+
+#[test]
+fn test_snapshotmaxcount() {
+    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()));
+    assert!(wrapper.execute_operation(vec!["-o", "snapshotmaxcount"]).is_ok());
+}
+
+#[test]
+fn test_snapshotrestore() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_supports_snapshots().returning(|| true);
+    mock.expect_snapshot_restore()
+        .withf(|id: &u32| *id == 1)
+        .times(1)
+        .returning(|_| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "snapshotrestore", "-s", "1"])
+        .is_ok());
+}
+
+#[test]
+fn test_getkvsfilename() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_kvs_filename()
+        .withf(|id: &u32| *id == 1)
+        .times(1)
+        .returning(|id| format!("kvs_0_{}.json", id));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "getkvsfilename", "-s", "1"])
+        .is_ok());
+}
+
+#[test]
+fn test_gethashfilename() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_hash_filename()
+        .withf(|id: &u32| *id == 1)
+        .times(1)
+        .returning(|id| format!("kvs_0_{}.hash", id));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "gethashfilename", "-s", "1"])
+        .is_ok());
+}
+
+#[test]
+fn test_createtestdata() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "number" && matches!(value, KvsValue::Number(n) if n == &123.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "bool" && matches!(value, KvsValue::Boolean(b) if *b)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "string" && matches!(value, KvsValue::String(s) if s == "First")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| key == "null" && matches!(value, KvsValue::Null))
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "array"
+                && matches!(
+                    value,
+                    KvsValue::Array(vec) if vec.len() == 3
+                        && matches!(vec[0], KvsValue::Number(n) if n == 456.0)
+                        && matches!(vec[1], KvsValue::Boolean(b) if !b)
+                        && matches!(&vec[2], KvsValue::String(s) if s == "Second")
+                )
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "object"
+                && matches!(
+                    value,
+                    KvsValue::Object(hm) if hm.len() == 5
+                        && hm.get("sub-number").map(|v| matches!(v, KvsValue::Number(n) if n == &789.0)).unwrap_or(false)
+                        && hm.get("sub-bool").map(|v| matches!(v, KvsValue::Boolean(b) if *b)).unwrap_or(false)
+                        && hm.get("sub-string").map(|v| matches!(v, KvsValue::String(s) if s == "Third")).unwrap_or(false)
+                        && hm.get("sub-null").map(|v| matches!(v, KvsValue::Null)).unwrap_or(false)
+                        && hm.get("sub-array").map(|v| matches!(v, KvsValue::Array(vec) if vec.len() == 3
+                            && matches!(vec[0], KvsValue::Number(n) if n == 1246.0)
+                            && matches!(vec[1], KvsValue::Boolean(b) if !b)
+                            && matches!(&vec[2], KvsValue::String(s) if s == "Fourth")
+                        )).unwrap_or(false)
+                )
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "createtestdata"])
+        .is_ok());
+}
+
+#[test]
+fn test_namespaces() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "app.name".to_string(),
+            "app.port".to_string(),
+            "db.host".to_string(),
+            "standalone".to_string(),
+        ])
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "namespaces", "--sep", "."])
+        .unwrap();
+    let OperationOutcome::Namespaces(counts) = outcome else {
+        panic!("Expected namespace counts, got {:?}", outcome);
+    };
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts["app"], 2);
+    assert_eq!(counts["db"], 1);
+    assert_eq!(counts[""], 1);
+}
+
+#[test]
+fn test_setkey_idempotency_key_applies_once() {
+    let mut mock = MockKvsMock::new();
+    let applied: Arc<Mutex<Option<KvsValue>>> = Arc::new(Mutex::new(None));
+    let stored = applied.clone();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == APPLIED_IDS_KEY)
+        .returning(move |_| stored.lock().unwrap().clone().ok_or(ErrorCode::KeyNotFound));
+    let stored = applied.clone();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == APPLIED_IDS_KEY)
+        .times(1)
+        .returning(move |_, value| {
+            *stored.lock().unwrap() = Some(value);
+            Ok(())
+        });
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let args = vec![
+        "-o",
+        "setkey",
+        "-k",
+        "MyKey",
+        "-p",
+        "Hello World",
+        "--idempotency-key",
+        "req-42",
+    ];
+    assert!(matches!(
+        wrapper.execute_operation(args.clone()),
+        Ok(OperationOutcome::Done)
+    ));
+    assert!(matches!(
+        wrapper.execute_operation(args),
+        Ok(OperationOutcome::AlreadyApplied)
+    ));
+}
+
+#[test]
+fn test_idempotency_key_is_shared_between_wrapper_instances() {
+    let kvs = Arc::new(MemKvs::default());
+    let args = vec![
+        "-o",
+        "setkey",
+        "-k",
+        "MyKey",
+        "-p",
+        "1",
+        "--idempotency-key",
+        "req-7",
+    ];
+    let first = KvsToolWrapper::from_arc(kvs.clone());
+    assert!(matches!(
+        first.execute_operation(args.clone()),
+        Ok(OperationOutcome::Done)
+    ));
+    kvs.set_value("MyKey", KvsValue::Number(2.0)).unwrap();
+
+    // A fresh wrapper over the same backend, as after a restart, must not apply it again
+    let second = KvsToolWrapper::from_arc(kvs.clone());
+    assert!(matches!(
+        second.execute_operation(args),
+        Ok(OperationOutcome::AlreadyApplied)
+    ));
+    assert!(matches!(kvs.get_value_raw("MyKey"), Ok(KvsValue::Number(n)) if n == 2.0));
+}
+
+#[test]
+fn test_normalize_number_encodings_compare_equal() {
+    // NaNs with different bit patterns only compare equal once normalized
+    let nan = KvsValue::Number(f64::NAN);
+    let other_nan = KvsValue::Number(-f64::NAN);
+    assert!(kvs_eq(&nan, &other_nan));
+    assert_eq!(kvs_hash(&nan), kvs_hash(&other_nan));
+
+    let a = convert_json_to_kvs(&r#"{"n":0,"list":[1,2.0]}"#.parse::<JsonValue>().unwrap());
+    let b = KvsValue::Object(HashMap::from([
+        ("n".to_string(), KvsValue::Number(-0.0)),
+        (
+            "list".to_string(),
+            KvsValue::Array(vec![KvsValue::Number(1.0), KvsValue::Number(2.0)]),
+        ),
+    ]));
+    assert!(kvs_eq(&a, &b));
+    assert_eq!(kvs_hash(&a), kvs_hash(&b));
+}
+
+#[test]
+fn test_normalize_leaves_strings_unchanged() {
+    let value = KvsValue::String(" 123.0 ".to_string());
+    assert!(matches!(normalize(&value), KvsValue::String(s) if s == " 123.0 "));
+    assert!(!kvs_eq(&value, &KvsValue::Number(123.0)));
+}
+
+// Writes a test input file into the temp directory and returns its path
+fn write_temp_file(name: &str, content: &str) -> String {
+    let path = std::env::temp_dir().join(name);
+    fs::write(&path, content).expect("Failed to write temp file");
+    path.to_string_lossy().into_owned()
+}
+
+// Shorthands for building KvsValues in test setup
+fn kv_num(n: impl Into<f64>) -> KvsValue {
+    KvsValue::Number(n.into())
+}
+
+fn kv_str(s: &str) -> KvsValue {
+    KvsValue::String(s.to_string())
+}
+
+fn kv_bool(b: bool) -> KvsValue {
+    KvsValue::Boolean(b)
+}
+
+fn kv_null() -> KvsValue {
+    KvsValue::Null
+}
+
+fn kv_arr(items: impl IntoIterator<Item = KvsValue>) -> KvsValue {
+    KvsValue::Array(items.into_iter().collect())
+}
+
+fn kv_obj<'a>(members: impl IntoIterator<Item = (&'a str, KvsValue)>) -> KvsValue {
+    KvsValue::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+#[test]
+fn test_plan_reports_create_update_delete() {
+    let file = write_temp_file(
+        "kvs_tool_plan_desired.json",
+        r#"{"new":1,"same":"x","changed":true}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "same".to_string(),
+            "changed".to_string(),
+            "gone".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "same")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("x".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "changed")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Boolean(false)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "plan", "-f", &file])
+        .unwrap();
+    let OperationOutcome::Plan(plan) = outcome else {
+        panic!("Expected a change plan, got {:?}", outcome);
+    };
+    assert_eq!(plan.create, vec!["new"]);
+    assert_eq!(plan.update, vec!["changed"]);
+    assert_eq!(plan.delete, vec!["gone"]);
+}
+
+#[test]
+fn test_apply_with_prune() {
+    let file = write_temp_file(
+        "kvs_tool_apply_desired.json",
+        r#"{"new":1,"same":"x","changed":true}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "same".to_string(),
+            "changed".to_string(),
+            "gone".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "same")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("x".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "changed")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Boolean(false)));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "new" && matches!(value, KvsValue::Number(n) if *n == 1.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "changed" && matches!(value, KvsValue::Boolean(b) if *b)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_remove_key()
+        .withf(|key: &str| key == "gone")
+        .times(1)
+        .returning(|_| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "apply", "-f", &file, "--prune"])
+        .unwrap();
+    let OperationOutcome::Applied(plan) = outcome else {
+        panic!("Expected an applied plan, got {:?}", outcome);
+    };
+    assert_eq!(plan.delete, vec!["gone"]);
+}
+
+#[test]
+fn test_apply_without_prune_keeps_extra_keys() {
+    let file = write_temp_file("kvs_tool_apply_no_prune.json", r#"{"new":1}"#);
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["extra".to_string()]));
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "new")
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_remove_key().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "apply", "-f", &file])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_only_if_changed_skips_identical_value() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(15.0)));
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec![
+        "-o",
+        "setkey",
+        "-k",
+        "MyKey",
+        "-p",
+        "15",
+        "--only-if-changed",
+    ]);
+    assert!(matches!(outcome, Ok(OperationOutcome::Unchanged)));
+}
+
+#[test]
+fn test_setkey_only_if_changed_writes_different_value() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(14.0)));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "MyKey" && matches!(value, KvsValue::Number(n) if *n == 15.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec![
+        "-o",
+        "setkey",
+        "-k",
+        "MyKey",
+        "-p",
+        "15",
+        "--only-if-changed",
+    ]);
+    assert!(matches!(outcome, Ok(OperationOutcome::Done)));
+}
+
+#[test]
+fn test_repl_serves_repeated_getkey_from_cache() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_is_value_default()
+        .times(2)
+        .returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .times(2)
+        .returning(|_| Ok(KvsValue::String("Default".to_string())));
+    mock.expect_get_value_string()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok("Hello".to_string()));
+
+    let repl = Repl::new(Box::new(mock));
+    let results = repl.run("getkey -k MyKey\ngetkey -k MyKey\n".as_bytes());
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|r| r.is_ok()));
+}
+
+#[test]
+fn test_repl_mutation_invalidates_cache() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_is_value_default()
+        .times(2)
+        .returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .times(2)
+        .returning(|_| Ok(KvsValue::Null));
+    mock.expect_get_value_string()
+        .times(2)
+        .returning(|_| Ok("Hello".to_string()));
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+
+    let repl = Repl::new(Box::new(mock));
+    let results =
+        repl.run("getkey -k MyKey\nsetkey -k MyKey -p World\ngetkey -k MyKey\nexit\n".as_bytes());
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| r.is_ok()));
+}
+
+#[test]
+fn test_setkey_malformed_json_reports_position() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", r#"{"a":1,"b":}"#])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::JsonParserError);
+    let detail = err.detail.expect("Expected parse error detail");
+    assert!(
+        detail.contains("line:1") && detail.contains("col:"),
+        "Expected a position in the detail: {}",
+        detail
+    );
+}
+
+#[test]
+fn test_export_yaml_nested_indentation() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["db".to_string(), "name".to_string()]));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "db")
+        .times(1)
+        .returning(|_| {
+            Ok(KvsValue::Object(HashMap::from([
+                (
+                    "host".to_string(),
+                    KvsValue::String("localhost".to_string()),
+                ),
+                (
+                    "ports".to_string(),
+                    KvsValue::Array(vec![KvsValue::Number(80.0), KvsValue::Number(443.0)]),
+                ),
+                (
+                    "replica".to_string(),
+                    KvsValue::Object(HashMap::from([(
+                        "enabled".to_string(),
+                        KvsValue::Boolean(false),
+                    )])),
+                ),
+            ])))
+        });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "name")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("a: b".to_string())));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "export", "--format", "yaml"])
+        .unwrap();
+    let OperationOutcome::Rendered(yaml) = outcome else {
+        panic!("Expected rendered output, got {:?}", outcome);
+    };
+    assert_eq!(
+        yaml,
+        "db:\n  host: localhost\n  ports:\n    - 80\n    - 443\n  replica:\n    enabled: false\nname: \"a: b\"\n"
+    );
+}
+
+#[test]
+fn test_yaml_string_quoting() {
+    assert_eq!(yaml_string("plain"), "plain");
+    assert_eq!(yaml_string(" leading"), "\" leading\"");
+    assert_eq!(yaml_string("true"), "\"true\"");
+    assert_eq!(yaml_string("42"), "\"42\"");
+    assert_eq!(yaml_string("say \"hi\""), "say \"hi\"");
+    assert_eq!(yaml_string("a: \"b\""), "\"a: \\\"b\\\"\"");
+}
+
+#[test]
+fn test_import_sets_all_entries() {
+    let file = write_temp_file("kvs_tool_import_all.json", r#"{"a":1,"b":"two"}"#);
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "a" && matches!(value, KvsValue::Number(n) if *n == 1.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "b" && matches!(value, KvsValue::String(s) if s == "two")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "import", "-f", &file]);
+    assert!(matches!(outcome, Ok(OperationOutcome::Imported(2))));
+}
+
+#[test]
+fn test_import_max_keys_aborts() {
+    let file = write_temp_file(
+        "kvs_tool_import_max_keys.json",
+        r#"{"a":1,"b":2,"c":3,"d":4}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| ["a", "b", "c"].contains(&key))
+        .times(3)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "import", "-f", &file, "--max-keys", "3"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::QuotaExceeded);
+    assert!(err.detail.unwrap().contains("after 3 keys"));
+}
+
+#[test]
+fn test_swap_exchanges_values() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "A")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("a".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "B")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(2.0)));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "A" && matches!(value, KvsValue::Number(n) if *n == 2.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "B" && matches!(value, KvsValue::String(s) if s == "a")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "swap", "-k", "A", "--newkey", "B"])
+        .is_ok());
+}
+
+#[test]
+fn test_swap_failed_second_write_restores_first() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "A")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("a".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "B")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(2.0)));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "A" && matches!(value, KvsValue::Number(n) if *n == 2.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "B")
+        .times(1)
+        .returning(|_, _| Err(ErrorCode::PhysicalStorageFailure));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "A" && matches!(value, KvsValue::String(s) if s == "a")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "swap", "-k", "A", "--newkey", "B"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::PhysicalStorageFailure);
+}
+
+#[test]
+fn test_swap_missing_key_writes_nothing() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "A")
+        .returning(|_| Ok(true));
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "B")
+        .returning(|_| Ok(false));
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "swap", "-k", "A", "--newkey", "B"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::KeyNotFound);
+}
+
+#[test]
+fn test_setkey_expand_env_resolved() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "Url" && matches!(value, KvsValue::String(s) if s == "http://db.local:5432/app")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let env = HashMap::from([
+        ("HOST".to_string(), "db.local".to_string()),
+        ("PORT".to_string(), "5432".to_string()),
+    ]);
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_env(env);
+    assert!(wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Url",
+            "-p",
+            "http://${HOST}:${PORT}/app",
+            "--expand-env",
+        ])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_expand_env_unresolved_stays_literal() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "Greeting" && matches!(value, KvsValue::String(s) if s == "Hello ${MISSING}")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_env(HashMap::new());
+    assert!(wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Greeting",
+            "-p",
+            "Hello ${MISSING}",
+            "--expand-env",
+        ])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_expand_env_unresolved_strict_errors() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_env(HashMap::new());
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Greeting",
+            "-p",
+            "Hello ${MISSING}",
+            "--expand-env",
+            "--strict-env",
+        ])
+        .unwrap_err();
+    assert!(err.detail.unwrap().contains("MISSING"));
+}
+
+#[test]
+fn test_oversized_returns_keys_over_threshold_by_size() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "Small".to_string(),
+            "Medium".to_string(),
+            "Large".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Small")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("x".repeat(4))));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Medium")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("x".repeat(40))));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Large")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Array(vec![KvsValue::String("x".repeat(100)); 3])));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "oversized", "--bytes", "16"])
+        .unwrap();
+    let OperationOutcome::Sizes(sizes) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        sizes,
+        vec![("Large".to_string(), 300), ("Medium".to_string(), 40)]
+    );
+}
+
+fn patch_config_mock(expected: impl Fn(&KvsValue) -> bool + Send + 'static) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "config")
+        .times(1)
+        .returning(|_| {
+            Ok(KvsValue::Object(HashMap::from([
+                ("name".to_string(), KvsValue::String("demo".to_string())),
+                (
+                    "ports".to_string(),
+                    KvsValue::Array(vec![KvsValue::Number(80.0), KvsValue::Number(443.0)]),
+                ),
+            ])))
+        });
+    mock.expect_set_value()
+        .withf(move |key: &str, value: &KvsValue| key == "config" && expected(value))
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock
+}
+
+#[test]
+fn test_patch_add() {
+    let mock = patch_config_mock(|value| {
+        let KvsValue::Object(obj) = value else {
+            return false;
+        };
+        matches!(obj.get("debug"), Some(KvsValue::Boolean(true)))
+            && matches!(obj.get("ports"), Some(KvsValue::Array(arr))
+                if arr.len() == 3 && matches!(arr[1], KvsValue::Number(n) if n == 8080.0))
+    });
+    let file = write_temp_file(
+        "kvs_tool_patch_add.json",
+        r#"[{"op": "add", "path": "/debug", "value": true},
+            {"op": "add", "path": "/ports/1", "value": 8080}]"#,
+    );
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "patch", "-k", "config", "-f", &file])
+        .is_ok());
+}
+
+#[test]
+fn test_patch_remove() {
+    let mock = patch_config_mock(|value| {
+        let KvsValue::Object(obj) = value else {
+            return false;
+        };
+        !obj.contains_key("name")
+            && matches!(obj.get("ports"), Some(KvsValue::Array(arr))
+                if arr.len() == 1 && matches!(arr[0], KvsValue::Number(n) if n == 443.0))
+    });
+    let file = write_temp_file(
+        "kvs_tool_patch_remove.json",
+        r#"[{"op": "remove", "path": "/name"}, {"op": "remove", "path": "/ports/0"}]"#,
+    );
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "patch", "-k", "config", "-f", &file])
+        .is_ok());
+}
+
+#[test]
+fn test_patch_replace() {
+    let mock = patch_config_mock(|value| {
+        let KvsValue::Object(obj) = value else {
+            return false;
+        };
+        matches!(obj.get("name"), Some(KvsValue::String(s)) if s == "prod")
+    });
+    let file = write_temp_file(
+        "kvs_tool_patch_replace.json",
+        r#"[{"op": "replace", "path": "/name", "value": "prod"}]"#,
+    );
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "patch", "-k", "config", "-f", &file])
+        .is_ok());
+}
+
+#[test]
+fn test_patch_unresolved_path_writes_nothing() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .times(1)
+        .returning(|_| Ok(KvsValue::Object(HashMap::new())));
+    mock.expect_set_value().times(0);
+    let file = write_temp_file(
+        "kvs_tool_patch_unresolved.json",
+        r#"[{"op": "replace", "path": "/missing", "value": 1}]"#,
+    );
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "patch", "-k", "config", "-f", &file])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    assert!(err.detail.unwrap().contains("/missing"));
+}
+
+#[test]
+fn test_setkey_verify_after_write_matches() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Limit")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(10.0)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Limit",
+            "-p",
+            "10",
+            "--verify-after-write",
+        ])
+        .is_ok());
+}
+
+#[test]
+fn test_setkey_verify_after_write_detects_coercion() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+    // Backend stored the number as a string
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Limit")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("10".to_string())));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Limit",
+            "-p",
+            "10",
+            "--verify-after-write",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+}
+
+#[test]
+fn test_setkey_without_verify_does_not_read_back() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+    mock.expect_get_value_raw().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Limit", "-p", "10"])
+        .is_ok());
+}
+
+#[test]
+fn test_shared_wrapper_concurrent_gets() {
+    let defaults: HashMap<String, KvsValue> = (0..8)
+        .map(|i| (format!("key{}", i), KvsValue::Number(i as f64)))
+        .collect();
+    let kvs = Arc::new(MemKvs::with_defaults(defaults));
+    kvs.set_value("key0", KvsValue::String("set".to_string()))
+        .unwrap();
+    let wrapper = KvsToolWrapper::shared(Arc::clone(&kvs));
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let wrapper = Arc::clone(&wrapper);
+            std::thread::spawn(move || {
+                let key = format!("key{}", i);
+                for _ in 0..100 {
+                    wrapper
+                        .execute_operation(vec!["-o", "getkey", "-k", &key])
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(kvs.get_value_string("key0").unwrap(), "\"set\"");
+    assert_eq!(kvs.get_value_string("key7").unwrap(), "7");
+}
+
+#[test]
+fn test_listkeys_skip_tombstones() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["Live".to_string(), "Removed".to_string()]));
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "Live")
+        .times(1)
+        .returning(|_| Ok(true));
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "Removed")
+        .times(1)
+        .returning(|_| Ok(false));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "listkeys", "--skip-tombstones"])
+        .unwrap();
+    let OperationOutcome::Keys { names: keys, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(keys, vec!["Live".to_string()]);
+}
+
+#[test]
+fn test_changedsince_reports_changed_and_added_keys() {
+    let snapshot = write_temp_file(
+        "kvs_tool_changedsince_1.json",
+        r#"{"Same": 1, "Changed": "old"}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_count().returning(|| 1);
+    mock.expect_get_kvs_filename()
+        .withf(|id: &u32| *id == 1)
+        .times(1)
+        .returning(move |_| snapshot.clone());
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "Same".to_string(),
+            "Changed".to_string(),
+            "Added".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Same")
+        .returning(|_| Ok(KvsValue::Number(1.0)));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Changed")
+        .returning(|_| Ok(KvsValue::String("new".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Added")
+        .returning(|_| Ok(KvsValue::Boolean(true)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "changedsince", "-s", "1"])
+        .unwrap();
+    let OperationOutcome::Values(changed) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(changed.len(), 2);
+    assert!(matches!(changed.get("Changed"), Some(KvsValue::String(s)) if s == "new"));
+    assert!(matches!(
+        changed.get("Added"),
+        Some(KvsValue::Boolean(true))
+    ));
+}
+
+#[test]
+fn test_query_type_filter() {
+    let predicate = Predicate::parse("type=string").unwrap();
+    assert!(predicate.matches(&KvsValue::String("100".to_string())));
+    assert!(!predicate.matches(&KvsValue::Number(100.0)));
+    assert!(!predicate.matches(&KvsValue::Null));
+
+    let predicate = Predicate::parse("type != null").unwrap();
+    assert!(predicate.matches(&KvsValue::Boolean(false)));
+    assert!(!predicate.matches(&KvsValue::Null));
+}
+
+#[test]
+fn test_query_numeric_comparison() {
+    let predicate = Predicate::parse("value>100").unwrap();
+    assert!(predicate.matches(&KvsValue::Number(100.5)));
+    assert!(!predicate.matches(&KvsValue::Number(100.0)));
+    // A string is never ordered against a number
+    assert!(!predicate.matches(&KvsValue::String("200".to_string())));
+
+    let predicate = Predicate::parse("value <= -1.5").unwrap();
+    assert!(predicate.matches(&KvsValue::Number(-1.5)));
+    assert!(!predicate.matches(&KvsValue::Number(0.0)));
+}
+
+#[test]
+fn test_query_combined_predicate() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "Big".to_string(),
+            "Small".to_string(),
+            "Text".to_string(),
+            "Flag".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Big")
+        .returning(|_| Ok(KvsValue::Number(500.0)));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Small")
+        .returning(|_| Ok(KvsValue::Number(5.0)));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Text")
+        .returning(|_| Ok(KvsValue::String("on".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Flag")
+        .returning(|_| Ok(KvsValue::Boolean(true)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "query",
+            "--where",
+            r#"(type=number && value>100) || value="on""#,
+        ])
+        .unwrap();
+    let OperationOutcome::Keys { names: keys, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(keys, vec!["Big".to_string(), "Text".to_string()]);
+
+    assert!(Predicate::parse("value >").is_err());
+    assert!(Predicate::parse("(type=number").is_err());
+    assert!(Predicate::parse("size=1").is_err());
+}
+
+#[test]
+fn test_read_only_blocks_mutations() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(0);
+    mock.expect_remove_key().times(0);
+    mock.expect_reset().times(0);
+    mock.expect_snapshot_restore().times(0);
+    mock.expect_get_all_keys().returning(|| Ok(Vec::new()));
+    mock.expect_snapshot_count().returning(|| 0);
+    mock.expect_supports_snapshots().returning(|| true);
+
+    let wrapper = KvsToolWrapper::new(Box::new(ReadOnlyKvs::new(Box::new(mock))));
+    for args in [
+        vec!["-o", "setkey", "-k", "Key", "-p", "Value"],
+        vec!["-o", "removekey", "-k", "Key"],
+        vec!["-o", "reset"],
+        vec!["-o", "snapshotrestore", "-s", "1"],
+    ] {
+        let err = wrapper.execute_operation(args).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnmappedError);
+    }
+}
+
+#[test]
+fn test_read_only_passes_reads_through() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(1).returning(|_| Ok(true));
+    mock.expect_is_value_default()
+        .times(1)
+        .returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .times(1)
+        .returning(|_| Ok(KvsValue::Null));
+    mock.expect_get_value_string()
+        .times(1)
+        .returning(|_| Ok("Value".to_string()));
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["Key".to_string()]));
+
+    let wrapper = KvsToolWrapper::new(Box::new(ReadOnlyKvs::new(Box::new(mock))));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Key"])
+        .is_ok());
+    assert!(wrapper.execute_operation(vec!["-o", "listkeys"]).is_ok());
+}
+
+#[test]
+fn test_export_sort_keys_orders_nested_objects() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["Servers".to_string()]));
+    mock.expect_get_value_raw().times(1).returning(|_| {
+        let server = |name: &str, port: f64| {
+            KvsValue::Object(HashMap::from([
+                ("port".to_string(), KvsValue::Number(port)),
+                ("host".to_string(), KvsValue::String(name.to_string())),
+            ]))
+        };
+        Ok(KvsValue::Array(vec![server("a", 1.0), server("b", 2.0)]))
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "export", "--sort-keys"])
+        .unwrap();
+    let OperationOutcome::Rendered(json) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        json,
+        r#"{"Servers":[{"host":"a","port":1},{"host":"b","port":2}]}"#
+    );
+}
+
+#[test]
+fn test_countvalue_counts_equal_values() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "A".to_string(),
+            "B".to_string(),
+            "C".to_string(),
+            "D".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "A" || key == "C")
+        .times(2)
+        .returning(|_| {
+            Ok(KvsValue::Object(HashMap::from([(
+                "todo".to_string(),
+                KvsValue::Boolean(true),
+            )])))
+        });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "B")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("{\"todo\":true}".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "D")
+        .times(1)
+        .returning(|_| {
+            Ok(KvsValue::Object(HashMap::from([(
+                "todo".to_string(),
+                KvsValue::Boolean(false),
+            )])))
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "countvalue", "-p", r#"{"todo": true}"#])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Count(2)));
+}
+
+#[test]
+fn test_setkey_backup_snapshots_before_write() {
+    let mut mock = MockKvsMock::new();
+    let mut seq = mockall::Sequence::new();
+    mock.expect_snapshot_create()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| Ok(1));
+    mock.expect_set_value()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Key", "-p", "Value", "--backup"])
+        .unwrap();
+    assert!(matches!(
+        outcome,
+        OperationOutcome::BackedUp { snapshot_id: 1, outcome } if matches!(*outcome, OperationOutcome::Done)
+    ));
+}
+
+#[test]
+fn test_setkey_without_backup_does_not_snapshot() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_create().times(0);
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Key", "-p", "Value"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+}
+
+#[test]
+fn test_scenario_fails_second_set_value() {
+    let scenario = write_temp_file(
+        "kvs_tool_scenario_second_set.txt",
+        "# storage fills up on the second write\nset_value 2 OutOfStorageSpace\n",
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "First")
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "Third")
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let kvs = ScenarioKvs::from_file(Box::new(mock), &scenario).unwrap();
+    let wrapper = KvsToolWrapper::new(Box::new(kvs));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "First", "-p", "1"])
+        .is_ok());
+    let err = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Second", "-p", "2"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::OutOfStorageSpace);
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Third", "-p", "3"])
+        .is_ok());
+}
+
+#[test]
+fn test_scenario_rejects_malformed_line() {
+    let scenario = write_temp_file(
+        "kvs_tool_scenario_malformed.txt",
+        "set_value two KeyNotFound\n",
+    );
+    let err = ScenarioKvs::from_file(Box::new(MockKvsMock::new()), &scenario)
+        .err()
+        .unwrap();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+}
+
+#[test]
+fn test_getmany_parallel_matches_sequential() {
+    let kvs = Arc::new(MemKvs::default());
+    let mut keys = Vec::new();
+    for i in 0..25 {
+        let key = format!("key{}", i);
+        kvs.set_value(&key, KvsValue::Number(i as f64)).unwrap();
+        keys.push(key);
+    }
+    let keys = keys.join(",");
+    let wrapper = KvsToolWrapper::shared(kvs);
+
+    let run = |workers: &str| match wrapper.execute_operation(vec![
+        "-o",
+        "getmany",
+        "--keys",
+        &keys,
+        "--parallel",
+        workers,
+    ]) {
+        Ok(OperationOutcome::Strings(values)) => values,
+        other => panic!("Unexpected result: {:?}", other),
+    };
+    let sequential = run("1");
+    assert_eq!(sequential.len(), 25);
+    assert_eq!(sequential[24], "24");
+    assert_eq!(run("4"), sequential);
+    assert_eq!(run("64"), sequential);
+}
+
+#[test]
+fn test_getmany_parallel_falls_back_for_mock() {
+    let mut mock = MockKvsMock::new();
+    let mut seq = mockall::Sequence::new();
+    for key in ["A", "B"] {
+        mock.expect_get_value_string()
+            .withf(move |k: &str| k == key)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|k| Ok(k.to_lowercase()));
+    }
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "getmany", "--keys", "A,B", "--parallel", "2"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Strings(values) if values == ["a", "b"]));
+}
+
+#[test]
+fn test_lint_flags_malformed_values() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "Clean".to_string(),
+            "Malformed".to_string(),
+            "Truncated".to_string(),
+        ])
+    });
+    mock.expect_get_value_string()
+        .withf(|key: &str| key == "Clean")
+        .times(1)
+        .returning(|_| Ok(r#"{"a": [1, 2]}"#.to_string()));
+    mock.expect_get_value_string()
+        .withf(|key: &str| key == "Malformed")
+        .times(1)
+        .returning(|_| Ok(r#"{"a": "#.to_string()));
+    mock.expect_get_value_string()
+        .withf(|key: &str| key == "Truncated")
+        .times(1)
+        .returning(|_| Ok(r#""{\"a\": 1""#.to_string()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "lint"]).unwrap();
+    let OperationOutcome::Keys { names: keys, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(keys, vec!["Malformed".to_string(), "Truncated".to_string()]);
+}
+
+#[test]
+fn test_kv_builder_matches_hand_built_value() {
+    let built = kv_obj([
+        ("a", kv_num(1)),
+        ("b", kv_arr([kv_bool(true), kv_null()])),
+        ("c", kv_obj([("d", kv_str("e")), ("f", kv_num(2.5))])),
+    ]);
+    let hand_built = KvsValue::Object(HashMap::from([
+        ("a".to_string(), KvsValue::Number(1.0)),
+        (
+            "b".to_string(),
+            KvsValue::Array(vec![KvsValue::Boolean(true), KvsValue::Null]),
+        ),
+        (
+            "c".to_string(),
+            KvsValue::Object(HashMap::from([
+                ("d".to_string(), KvsValue::String("e".to_string())),
+                ("f".to_string(), KvsValue::Number(2.5)),
+            ])),
+        ),
+    ]));
+    assert!(kvs_eq(&built, &hand_built));
+}
+
+fn migrate_filenames_mock() -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_count().returning(|| 10);
+    mock.expect_get_kvs_filename()
+        .returning(|id| format!("kvs_0_{}.json", id));
+    mock.expect_get_hash_filename()
+        .returning(|id| format!("kvs_0_{}.hash", id));
+    mock
+}
+
+#[test]
+fn test_migratefilenames_pads_ids() {
+    let mut mock = migrate_filenames_mock();
+    mock.expect_rename_snapshot_files()
+        .times(10)
+        .returning(|_, _, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "migratefilenames", "--pad", "3"])
+        .unwrap();
+    let OperationOutcome::Renames(renames) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(renames.len(), 20);
+    for (old, new) in [
+        ("kvs_0_1.json", "kvs_0_001.json"),
+        ("kvs_0_1.hash", "kvs_0_001.hash"),
+        ("kvs_0_10.json", "kvs_0_010.json"),
+        ("kvs_0_10.hash", "kvs_0_010.hash"),
+    ] {
+        assert!(renames.contains(&(old.to_string(), new.to_string())));
+    }
+}
+
+#[test]
+fn test_migratefilenames_dry_run_renames_nothing() {
+    let mut mock = migrate_filenames_mock();
+    mock.expect_rename_snapshot_files().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "migratefilenames", "--pad", "3", "--dry-run"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Renames(renames) if renames.len() == 20));
+}
+
+#[test]
+fn test_setkey_explain_traces_unchanged_skip() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(1).returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(5.0)));
+    mock.expect_set_value().times(0);
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_sink(sink.clone());
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Count",
+            "-p",
+            "5",
+            "--only-if-changed",
+            "--explain",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Unchanged));
+    assert_eq!(
+        *sink.lock().unwrap(),
+        vec![
+            "explain: parsed payload as number".to_string(),
+            "explain: key existed with an equal value, unchanged, skipped".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_setkey_without_explain_traces_nothing() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_sink(sink.clone());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "Count", "-p", "5"])
+        .is_ok());
+    assert!(sink.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_setkey_key_length_counts_utf8_bytes() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(1).returning(|_, _| Ok(()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+
+    // 127 two-byte characters plus one ASCII byte: 128 characters, exactly 255 bytes
+    let at_limit = format!("{}a", "é".repeat(127));
+    assert_eq!(at_limit.len(), MAX_KEY_BYTES);
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", &at_limit, "-p", "1"])
+        .is_ok());
+
+    // Also 128 characters, but 256 bytes
+    let over_limit = "é".repeat(128);
+    let err = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", &over_limit, "-p", "1"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    assert!(err.detail.unwrap().contains("256 bytes (128 characters)"));
+}
+
+#[test]
+fn test_import_rejects_multibyte_key_over_limit() {
+    let key = "键".repeat(86); // 86 characters, 258 bytes
+    let file = write_temp_file(
+        "kvs_tool_import_long_key.json",
+        &format!(r#"{{"short": 1, "{}": 2}}"#, key),
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "import", "-f", &file])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+}
+
+#[test]
+fn test_import_since_file_skips_unchanged_entries() {
+    let hash_file = std::env::temp_dir().join("kvs_tool_import_since.hashes");
+    let _ = fs::remove_file(&hash_file);
+    let hash_file = hash_file.to_string_lossy().into_owned();
+
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "Same")
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "Changed")
+        .times(2)
+        .returning(|_, _| Ok(()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+
+    let first = write_temp_file(
+        "kvs_tool_import_since_1.json",
+        r#"{"Same": [1, 2], "Changed": "old"}"#,
+    );
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "import",
+            "-f",
+            &first,
+            "--since-file",
+            &hash_file,
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(2)));
+
+    let second = write_temp_file(
+        "kvs_tool_import_since_2.json",
+        r#"{"Same": [1, 2], "Changed": "new"}"#,
+    );
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "import",
+            "-f",
+            &second,
+            "--since-file",
+            &hash_file,
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(1)));
+}
+
+fn clone_source_mock() -> MockKvsMock {
+    let mut source = MockKvsMock::new();
+    source
+        .expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["A".to_string(), "B".to_string()]));
+    source
+        .expect_get_value_raw()
+        .withf(|key: &str| key == "A")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(1.0)));
+    source
+        .expect_get_value_raw()
+        .withf(|key: &str| key == "B")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("b".to_string())));
+    source
+}
+
+#[test]
+fn test_cloneto_copies_every_key() {
+    let mut target = MockKvsMock::new();
+    target
+        .expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "A" && matches!(value, KvsValue::Number(n) if *n == 1.0)
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    target
+        .expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "B" && matches!(value, KvsValue::String(s) if s == "b")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper =
+        KvsToolWrapper::new(Box::new(clone_source_mock())).with_instance(2, Arc::new(target));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "cloneto", "--instance", "2"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(2)));
+}
+
+#[test]
+fn test_cloneto_atomic_restores_target_on_failure() {
+    let target = Arc::new(MemKvs::default());
+    target.set_value("A", KvsValue::Number(0.0)).unwrap();
+    let mut failing_target = MockKvsMock::new();
+    let mem = Arc::clone(&target);
+    failing_target
+        .expect_key_exists()
+        .returning(move |key| mem.key_exists(key));
+    let mem = Arc::clone(&target);
+    failing_target
+        .expect_get_value_raw()
+        .returning(move |key| mem.get_value_raw(key));
+    let mem = Arc::clone(&target);
+    failing_target
+        .expect_remove_key()
+        .returning(move |key| mem.remove_key(key));
+    // Writing "B" fails; every other write reaches the in-memory store
+    let mem = Arc::clone(&target);
+    failing_target
+        .expect_set_value()
+        .returning(move |key, value| match key {
+            "B" => Err(ErrorCode::OutOfStorageSpace),
+            _ => mem.set_value(key, value),
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(clone_source_mock()))
+        .with_instance(2, Arc::new(failing_target));
+    let err = wrapper
+        .execute_operation(vec!["-o", "cloneto", "--instance", "2", "--atomic"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::OutOfStorageSpace);
+    assert!(matches!(target.get_value_raw("A"), Ok(KvsValue::Number(n)) if n == 0.0));
+    assert!(!target.key_exists("B").unwrap());
+}
+
+#[test]
+fn test_sample_same_seed_same_sample() {
+    let kvs = Arc::new(MemKvs::default());
+    for i in 0..50 {
+        kvs.set_value(&format!("key{}", i), KvsValue::Number(i as f64))
+            .unwrap();
+    }
+    let wrapper = KvsToolWrapper::shared(kvs);
+    let sample = |seed: &str| match wrapper
+        .execute_operation(vec!["-o", "sample", "--count", "5", "--seed", seed])
+    {
+        Ok(OperationOutcome::Values(values)) => {
+            let mut keys: Vec<String> = values.into_keys().collect();
+            keys.sort();
+            keys
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    };
+
+    let first = sample("42");
+    assert_eq!(first.len(), 5);
+    assert_eq!(sample("42"), first);
+    assert_ne!(sample("7"), first);
+}
+
+#[test]
+fn test_timing_logs_slow_calls() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        std::thread::sleep(Duration::from_millis(30));
+        Ok(Vec::new())
+    });
+    mock.expect_supports_snapshots().returning(|| true);
+    mock.expect_snapshot_count().times(1).returning(|| 0);
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let kvs = TimingKvs::new(Box::new(mock), Duration::from_millis(10), sink.clone());
+    let wrapper = KvsToolWrapper::new(Box::new(kvs));
+    assert!(wrapper.execute_operation(vec!["-o", "listkeys"]).is_ok());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "snapshotcount"])
+        .is_ok());
+
+    let lines = sink.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("warning: slow get_all_keys took"));
+}
+
+#[test]
+fn test_resolve_follows_two_hops() {
+    let kvs = MemKvs::default();
+    kvs.set_value("start", kv_obj([("$ref", kv_str("middle"))]))
+        .unwrap();
+    kvs.set_value("middle", kv_obj([("$ref", kv_str("end"))]))
+        .unwrap();
+    kvs.set_value("end", kv_obj([("port", kv_num(8080))]))
+        .unwrap();
+
+    let wrapper = KvsToolWrapper::new(Box::new(kvs));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "resolve", "-k", "start"])
+        .unwrap();
+    let OperationOutcome::Value(value) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert!(kvs_eq(&value, &kv_obj([("port", kv_num(8080))])));
+}
+
+#[test]
+fn test_resolve_detects_self_reference() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "loop")
+        .times(1)
+        .returning(|_| Ok(kv_obj([("$ref", kv_str("loop"))])));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "resolve", "-k", "loop"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    assert_eq!(err.detail.unwrap(), "Reference cycle: loop -> loop");
+}
+
+#[test]
+fn test_reset_reports_removed_key_count() {
+    let mut mock = MockKvsMock::new();
+    let mut seq = mockall::Sequence::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| Ok(vec!["A".to_string(), "B".to_string(), "C".to_string()]));
+    mock.expect_snapshot_count().times(1).returning(|| 2);
+    mock.expect_reset()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "reset"]).unwrap();
+    assert!(matches!(
+        outcome,
+        OperationOutcome::Reset {
+            keys_removed: Some(3),
+            snapshots: 2
+        }
+    ));
+}
+
+#[test]
+fn test_reset_no_count_skips_enumeration() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(0);
+    mock.expect_snapshot_count().times(1).returning(|| 0);
+    mock.expect_reset().times(1).returning(|| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "reset", "--no-count"])
+        .unwrap();
+    assert!(matches!(
+        outcome,
+        OperationOutcome::Reset {
+            keys_removed: None,
+            snapshots: 0
+        }
+    ));
+}
+
+#[test]
+fn test_snapshots_unsupported() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_supports_snapshots().returning(|| false);
+    mock.expect_snapshot_restore().times(0);
+    mock.expect_snapshot_count().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    for args in [
+        vec!["-o", "snapshotrestore", "-s", "1"],
+        vec!["-o", "snapshotcount"],
+    ] {
+        let err = wrapper.execute_operation(args).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnmappedError);
+        assert_eq!(
+            err.detail.as_deref(),
+            Some("Snapshots are not supported by this store")
+        );
+    }
+}
+
+#[test]
+fn test_rekey_moves_prefixed_keys() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["old.a".to_string(), "other".to_string()]));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "old.a")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Number(1.0)));
+    let mut seq = mockall::Sequence::new();
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "new.a" && matches!(value, KvsValue::Number(n) if *n == 1.0)
+        })
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|_, _| Ok(()));
+    mock.expect_remove_key()
+        .withf(|key: &str| key == "old.a")
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|_| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "rekey",
+            "--strip-prefix",
+            "old.",
+            "--add-prefix",
+            "new.",
+        ])
+        .unwrap();
+    let OperationOutcome::Renames(renames) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(renames, vec![("old.a".to_string(), "new.a".to_string())]);
+}
+
+#[test]
+fn test_rekey_refuses_to_overwrite_existing_key() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["old.a".to_string(), "new.a".to_string()]));
+    mock.expect_set_value().times(0);
+    mock.expect_remove_key().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "rekey",
+            "--strip-prefix",
+            "old.",
+            "--add-prefix",
+            "new.",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+}
+
+#[test]
+fn test_rekey_handles_chained_names() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("a", kv_num(1)).unwrap();
+    kvs.set_value("xa", kv_num(2)).unwrap();
+
+    let wrapper = KvsToolWrapper::shared(Arc::clone(&kvs));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "rekey", "--add-prefix", "x"])
+        .is_ok());
+    let mut keys = kvs.get_all_keys().unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["xa".to_string(), "xxa".to_string()]);
+    assert!(kvs_eq(&kvs.get_value_raw("xa").unwrap(), &kv_num(1)));
+    assert!(kvs_eq(&kvs.get_value_raw("xxa").unwrap(), &kv_num(2)));
+}
+
+#[test]
+fn test_parse_snapshot_id_valid() {
+    let mut args = pico_args::Arguments::from_vec(vec!["-s".into(), "12".into()]);
+    assert_eq!(parse_snapshot_id(&mut args).unwrap(), 12);
+}
+
+#[test]
+fn test_parse_snapshot_id_non_numeric() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_kvs_filename().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "getkvsfilename", "-s", "abc"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidSnapshotId);
+    assert!(err
+        .detail
+        .unwrap()
+        .contains("'abc': expected a non-negative integer"));
+}
+
+#[test]
+fn test_parse_snapshot_id_negative() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_supports_snapshots().returning(|| true);
+    mock.expect_snapshot_restore().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "snapshotrestore", "-s", "-1"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidSnapshotId);
+    assert!(err.detail.unwrap().contains("'-1'"));
+}
+
+fn getkey_mock(value: KvsValue, value_string: &'static str) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().returning(|_| Ok(true));
+    mock.expect_is_value_default().returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .returning(|_| Ok(KvsValue::Null));
+    mock.expect_get_value_string()
+        .returning(move |_| Ok(value_string.to_string()));
+    mock.expect_get_value_raw()
+        .returning(move |_| Ok(value.clone()));
+    mock
+}
+
+#[test]
+fn test_getkey_output_null_renders_sentinel() {
+    let wrapper = KvsToolWrapper::new(Box::new(getkey_mock(KvsValue::Null, "")));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Empty", "--output-null", "null"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Rendered(text) if text == "null"));
+
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "getkey",
+            "-k",
+            "Empty",
+            "--output-null",
+            "<none>",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Rendered(text) if text == "<none>"));
+}
+
+#[test]
+fn test_getkey_output_null_leaves_nested_null() {
+    let value = kv_obj([("a", kv_null())]);
+    let wrapper = KvsToolWrapper::new(Box::new(getkey_mock(value, r#"{"a":null}"#)));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "getkey",
+            "-k",
+            "Nested",
+            "--output-null",
+            "<none>",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Rendered(text) if text == r#"{"a":null}"#));
+}
+
+#[test]
+fn test_assert_lists_mismatching_key() {
+    let file = write_temp_file(
+        "kvs_tool_assert_golden.json",
+        r#"{"same":"x","changed":true}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["same".to_string(), "changed".to_string()]));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "same")
+        .times(1)
+        .returning(|_| Ok(KvsValue::String("x".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "changed")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Boolean(false)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "assert", "-f", &file])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    let detail = err.detail.unwrap();
+    assert!(detail.ends_with(": differs 'changed'"));
+}
+
+#[test]
+fn test_dumpall_stream_matches_buffered() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("number", kv_num(1.5)).unwrap();
+    kvs.set_value("text", kv_str("a \"quoted\" value")).unwrap();
+    kvs.set_value(
+        "nested",
+        kv_obj([("list", kv_arr([kv_bool(true), kv_null()]))]),
+    )
+    .unwrap();
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::from_arc(kvs).with_sink(sink.clone());
+
+    let OperationOutcome::Rendered(buffered) =
+        wrapper.execute_operation(vec!["-o", "dumpall"]).unwrap()
+    else {
+        panic!("Expected rendered output");
+    };
+    assert!(wrapper
+        .execute_operation(vec!["-o", "dumpall", "--stream"])
+        .is_ok());
+    let streamed = sink.lock().unwrap().join("\n");
+    assert_eq!(
+        streamed.parse::<JsonValue>().unwrap(),
+        buffered.parse::<JsonValue>().unwrap()
+    );
+}
+
+#[test]
+fn test_dumpall_stream_empty_store() {
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(MemKvs::default())).with_sink(sink.clone());
+    assert!(wrapper
+        .execute_operation(vec!["-o", "dumpall", "--stream"])
+        .is_ok());
+    let streamed = sink.lock().unwrap().join("\n");
+    assert_eq!(
+        streamed.parse::<JsonValue>().unwrap(),
+        JsonValue::Object(HashMap::new())
+    );
+}
+
+fn setif_mock(flag: Option<KvsValue>, writes: usize) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    let exists = flag.is_some();
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "flag")
+        .returning(move |_| Ok(exists));
+    if let Some(flag) = flag {
+        mock.expect_get_value_raw()
+            .withf(|key: &str| key == "flag")
+            .returning(move |_| Ok(flag.clone()));
+    }
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "target" && matches!(value, KvsValue::Number(n) if *n == 42.0)
+        })
+        .times(writes)
+        .returning(|_, _| Ok(()));
+    mock
+}
+
+const SETIF_ARGS: [&str; 10] = [
+    "-o",
+    "setif",
+    "--cond-key",
+    "flag",
+    "--cond-value",
+    "true",
+    "-k",
+    "target",
+    "-p",
+    "42",
+];
+
+#[test]
+fn test_setif_condition_met_writes() {
+    let wrapper = KvsToolWrapper::new(Box::new(setif_mock(Some(kv_bool(true)), 1)));
+    let outcome = wrapper.execute_operation(SETIF_ARGS.to_vec()).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+}
+
+#[test]
+fn test_setif_condition_not_met_skips() {
+    let wrapper = KvsToolWrapper::new(Box::new(setif_mock(Some(kv_bool(false)), 0)));
+    let outcome = wrapper.execute_operation(SETIF_ARGS.to_vec()).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Unchanged));
+
+    let wrapper = KvsToolWrapper::new(Box::new(setif_mock(None, 0)));
+    let outcome = wrapper.execute_operation(SETIF_ARGS.to_vec()).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Unchanged));
+}
+
+#[test]
+fn test_render_diff_shows_changed_nested_field() {
+    let old = kv_obj([
+        ("name", kv_str("demo")),
+        (
+            "db",
+            kv_obj([("host", kv_str("a")), ("port", kv_num(5432))]),
+        ),
+    ]);
+    let new = kv_obj([
+        ("name", kv_str("demo")),
+        (
+            "db",
+            kv_obj([("host", kv_str("a")), ("port", kv_num(6543))]),
+        ),
+    ]);
+    assert_eq!(render_diff(&old, &new), "- db.port: 5432\n+ db.port: 6543");
+
+    let old = kv_arr([kv_num(1)]);
+    let new = kv_arr([kv_num(1), kv_str("x")]);
+    assert_eq!(render_diff(&old, &new), "+ [1]: \"x\"");
+}
+
+#[test]
+fn test_plan_includes_diff_for_updated_key() {
+    let file = write_temp_file(
+        "kvs_tool_plan_diff.json",
+        r#"{"config": {"debug": true, "level": 2}}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["config".to_string()]));
+    mock.expect_get_value_raw()
+        .times(1)
+        .returning(|_| Ok(kv_obj([("debug", kv_bool(false)), ("level", kv_num(2))])));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "plan", "-f", &file])
+        .unwrap();
+    let OperationOutcome::Plan(plan) = outcome else {
+        panic!("Expected a change plan, got {:?}", outcome);
+    };
+    assert_eq!(plan.diffs["config"], "- debug: false\n+ debug: true");
+}
+
+#[test]
+fn test_import_parallel_last_write_wins_per_key() {
+    let file = write_temp_file(
+        "kvs_tool_import_parallel.json",
+        r#"[["a", 1], ["b", 1], ["a", 2], ["c", 3], ["a", 3], ["d", 4], ["b", 2]]"#,
+    );
+    for _ in 0..20 {
+        let kvs = Arc::new(MemKvs::default());
+        let wrapper = KvsToolWrapper::shared(Arc::clone(&kvs));
+        let outcome = wrapper
+            .execute_operation(vec!["-o", "import", "-f", &file, "--max-concurrency", "4"])
+            .unwrap();
+        assert!(matches!(outcome, OperationOutcome::Imported(7)));
+        for (key, expected) in [("a", 3), ("b", 2), ("c", 3), ("d", 4)] {
+            assert!(kvs_eq(&kvs.get_value_raw(key).unwrap(), &kv_num(expected)));
+        }
+    }
+}
+
+#[test]
+fn test_trimarrays_removes_only_trailing_nulls() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["Padded".to_string(), "Scalar".to_string()]));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Padded")
+        .times(1)
+        .returning(|_| {
+            Ok(kv_arr([
+                kv_num(1),
+                kv_null(),
+                kv_num(2),
+                kv_null(),
+                kv_null(),
+            ]))
+        });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Scalar")
+        .times(1)
+        .returning(|_| Ok(kv_null()));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "Padded" && kvs_eq(value, &kv_arr([kv_num(1), kv_null(), kv_num(2)]))
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "trimarrays"]).unwrap();
+    let OperationOutcome::Keys { names: keys, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(keys, vec!["Padded".to_string()]);
+}
+
+#[test]
+fn test_createtestdata_count_reports_type_histogram() {
+    let kvs = Arc::new(MemKvs::default());
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "createtestdata", "--count", "50", "--seed", "7"])
+        .unwrap();
+    let OperationOutcome::TypeCounts(type_counts) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        type_counts.iter().map(|(_, count)| count).sum::<usize>(),
+        50
+    );
+    let mut stored = HashMap::new();
+    for key in kvs.get_all_keys().unwrap() {
+        let value = kvs.get_value_raw(&key).unwrap();
+        *stored.entry(kvs_type_name(&value)).or_insert(0) += 1;
+    }
+    assert_eq!(stored, type_counts.into_iter().collect());
+}
+
+#[test]
+fn test_locate_returns_value_and_filename() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(|_| Ok(kv_str("Hello")));
+    mock.expect_get_kvs_filename()
+        .withf(|id: &u32| *id == 0)
+        .times(1)
+        .returning(|id| format!("kvs_0_{}.json", id));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "locate", "-k", "MyKey"])
+        .unwrap();
+    let OperationOutcome::Located { value, filename } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert!(kvs_eq(&value, &kv_str("Hello")));
+    assert_eq!(filename, "kvs_0_0.json");
+}
+
+#[test]
+fn test_compact_rewrites_only_non_canonical_values() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "Canonical".to_string(),
+            "NegativeZero".to_string(),
+            "Nested".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Canonical")
+        .times(1)
+        .returning(|_| Ok(kv_num(0)));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "NegativeZero")
+        .times(1)
+        .returning(|_| Ok(kv_arr([kv_num(1), kv_num(-0.0)])));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Nested")
+        .times(1)
+        .returning(|_| Ok(kv_obj([("a", kv_str("-0.0")), ("b", kv_null())])));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "NegativeZero"
+                && matches!(value, KvsValue::Array(arr)
+                    if matches!(arr[1], KvsValue::Number(n) if n.to_bits() == 0.0f64.to_bits()))
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "compact"]).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Count(1)));
+}
+
+#[test]
+fn test_listkeys_table_aligns_and_truncates() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("id", kv_num(7)).unwrap();
+    kvs.set_value("description", kv_str("a rather long description"))
+        .unwrap();
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::from_arc(kvs).with_sink(sink.clone());
+
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "listkeys",
+            "--with-values",
+            "--format",
+            "table",
+            "--width",
+            "12",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+    let lines = sink.lock().unwrap().clone();
+    assert_eq!(
+        lines,
+        vec![
+            "KEY          VALUE".to_string(),
+            "description  \"a rather l…".to_string(),
+            "id           7".to_string(),
+        ]
+    );
+}
+
+fn checktype_mock(value: KvsValue) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "MyKey")
+        .times(1)
+        .returning(move |_| Ok(value.clone()));
+    mock
+}
+
+#[test]
+fn test_checktype_matching_type() {
+    let wrapper = KvsToolWrapper::new(Box::new(checktype_mock(kv_obj([("a", kv_num(1))]))));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "checktype", "-k", "MyKey", "--type", "object"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+}
+
+#[test]
+fn test_checktype_mismatch_names_actual_type() {
+    let wrapper = KvsToolWrapper::new(Box::new(checktype_mock(kv_str("text"))));
+    let err = wrapper
+        .execute_operation(vec!["-o", "checktype", "-k", "MyKey", "--type", "object"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("'MyKey' has type string, expected object")
+    );
+}
+
+#[test]
+fn test_rekey_panic_restores_original_key() {
+    // The backend panics halfway through the rename, after the new key was written
+    let scenario = write_temp_file("kvs_tool_scenario_rekey_panic.txt", "remove_key 1 panic\n");
+    let store = MemKvs::default();
+    store.set_value("app.x", kv_str("original")).unwrap();
+    let kvs = ScenarioKvs::from_file(Box::new(store), &scenario).unwrap();
+    let wrapper = KvsToolWrapper::new(Box::new(kvs));
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        wrapper.execute_operation(vec![
+            "-o",
+            "rekey",
+            "--strip-prefix",
+            "app.",
+            "--add-prefix",
+            "svc.",
+        ])
+    }));
+    assert!(result.is_err());
+    assert!(kvs_eq(
+        &wrapper.kvs.get_value_raw("app.x").unwrap(),
+        &kv_str("original")
+    ));
+    assert!(!wrapper.kvs.key_exists("svc.x").unwrap());
+}
+
+#[test]
+fn test_listtyped_pairs_keys_with_types() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "name".to_string(),
+            "count".to_string(),
+            "tags".to_string(),
+            "unset".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .times(4)
+        .returning(|key| match key {
+            "name" => Ok(kv_str("kvs")),
+            "count" => Ok(kv_num(3)),
+            "tags" => Ok(kv_arr([kv_str("a")])),
+            _ => Ok(kv_null()),
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "listtyped"]).unwrap();
+    let OperationOutcome::Types(typed) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        typed,
+        vec![
+            ("count".to_string(), "number"),
+            ("name".to_string(), "string"),
+            ("tags".to_string(), "array"),
+            ("unset".to_string(), "null"),
+        ]
+    );
+}
+
+#[test]
+fn test_export_chunk_size_splits_sorted_keys() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(["e", "c", "a", "d", "b"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+    mock.expect_get_value_raw()
+        .times(5)
+        .returning(|key| Ok(kv_str(key)));
+    let out_dir = std::env::temp_dir().join("kvs_tool_export_chunks");
+    let _ = fs::remove_dir_all(&out_dir);
+    fs::create_dir_all(&out_dir).unwrap();
+    let out_dir = out_dir.to_string_lossy().into_owned();
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "export",
+            "--chunk-size",
+            "2",
+            "--out-dir",
+            &out_dir,
+        ])
+        .unwrap();
+    let OperationOutcome::Strings(chunks) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        chunks,
+        vec!["export_0.json", "export_1.json", "export_2.json"]
+    );
+    let index = fs::read_to_string(format!("{}/export_index.json", out_dir)).unwrap();
+    assert_eq!(
+        index,
+        r#"["export_0.json","export_1.json","export_2.json"]"#
+    );
+    for (chunk, expected) in chunks.iter().zip([&["a", "b"][..], &["c", "d"], &["e"]]) {
+        let values = read_key_value_file(&format!("{}/{}", out_dir, chunk)).unwrap();
+        let mut keys: Vec<&str> = values.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, expected);
+    }
+}
+
+#[test]
+fn test_import_merge_deep_merges_object_keys() {
+    let base = write_temp_file(
+        "kvs_tool_merge_base.json",
+        r#"{"db": {"host": "localhost", "pool": {"min": 1, "max": 4}}, "mode": "dev"}"#,
+    );
+    let site = write_temp_file(
+        "kvs_tool_merge_site.json",
+        r#"{"db": {"host": "db.site", "pool": {"max": 16}}, "mode": ["prod"]}"#,
+    );
+    let kvs = Arc::new(MemKvs::default());
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "import", "--merge", &base, &site])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(2)));
+    assert!(kvs_eq(
+        &kvs.get_value_raw("db").unwrap(),
+        &kv_obj([
+            ("host", kv_str("db.site")),
+            ("pool", kv_obj([("min", kv_num(1)), ("max", kv_num(16))])),
+        ])
+    ));
+    assert!(kvs_eq(
+        &kvs.get_value_raw("mode").unwrap(),
+        &kv_arr([kv_str("prod")])
+    ));
+}
+
+#[test]
+fn test_export_redacts_matching_leaf_keys() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys()
+        .times(1)
+        .returning(|| Ok(vec!["db".to_string()]));
+    mock.expect_get_value_raw().times(1).returning(|_| {
+        Ok(kv_obj([
+            ("user", kv_str("admin")),
+            ("password", kv_str("hunter2")),
+            ("replicas", kv_arr([kv_obj([("token", kv_str("abc"))])])),
+        ]))
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "export",
+            "--sort-keys",
+            "--redact",
+            "password,token",
+        ])
+        .unwrap();
+    let OperationOutcome::Rendered(json) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        json,
+        r#"{"db":{"password":"***","replicas":[{"token":"***"}],"user":"admin"}}"#
+    );
+}
+
+fn renamefield_mock(writes: usize) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "config")
+        .times(1)
+        .returning(|_| {
+            Ok(kv_obj([(
+                "db",
+                kv_obj([("oldname", kv_str("primary")), ("port", kv_num(5432))]),
+            )]))
+        });
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "config"
+                && kvs_eq(
+                    value,
+                    &kv_obj([(
+                        "db",
+                        kv_obj([("newname", kv_str("primary")), ("port", kv_num(5432))]),
+                    )]),
+                )
+        })
+        .times(writes)
+        .returning(|_, _| Ok(()));
+    mock
+}
+
+#[test]
+fn test_renamefield_renames_nested_field() {
+    let wrapper = KvsToolWrapper::new(Box::new(renamefield_mock(1)));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "renamefield",
+            "-k",
+            "config",
+            "--path",
+            "db.oldname",
+            "--to",
+            "newname",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+}
+
+#[test]
+fn test_renamefield_missing_source_field() {
+    let wrapper = KvsToolWrapper::new(Box::new(renamefield_mock(0)));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "renamefield",
+            "-k",
+            "config",
+            "--path",
+            "db.missing",
+            "--to",
+            "newname",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::KeyNotFound);
+    assert_eq!(err.detail.as_deref(), Some("config: no field 'db.missing'"));
+}
+
+fn copy_mock(target_exists: bool, writes: usize) -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Source")
+        .returning(|_| Ok(kv_str("value")));
+    mock.expect_key_exists()
+        .withf(|key: &str| key == "Target")
+        .returning(move |_| Ok(target_exists));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| key == "Target" && kvs_eq(value, &kv_str("value")))
+        .times(writes)
+        .returning(|_, _| Ok(()));
+    mock
+}
+
+#[test]
+fn test_copy_no_clobber_skips_existing_key() {
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(copy_mock(true, 0))).with_sink(sink.clone());
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "copy",
+            "-k",
+            "Source",
+            "--newkey",
+            "Target",
+            "--no-clobber",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Unchanged));
+    assert_eq!(
+        *sink.lock().unwrap(),
+        vec!["skipped 'Target': already exists".to_string()]
+    );
+}
+
+#[test]
+fn test_copy_no_clobber_writes_new_key() {
+    let wrapper = KvsToolWrapper::new(Box::new(copy_mock(false, 1)));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "copy",
+            "-k",
+            "Source",
+            "--newkey",
+            "Target",
+            "--no-clobber",
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+}
+
+#[test]
+fn test_import_no_clobber_keeps_existing_keys() {
+    let file = write_temp_file(
+        "kvs_tool_import_no_clobber.json",
+        r#"{"existing": 2, "new": 3}"#,
+    );
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("existing", kv_num(1)).unwrap();
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "import", "-f", &file, "--no-clobber"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(1)));
+    assert!(kvs_eq(&kvs.get_value_raw("existing").unwrap(), &kv_num(1)));
+    assert!(kvs_eq(&kvs.get_value_raw("new").unwrap(), &kv_num(3)));
+}
+
+#[test]
+fn test_listkeys_page_reports_total() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(["e", "c", "a", "d", "b"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "listkeys", "--offset", "1", "--limit", "2"])
+        .unwrap();
+    let OperationOutcome::Keys { names, total } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(names, vec!["b".to_string(), "c".to_string()]);
+    assert_eq!(total, 5);
+}
+
+#[test]
+fn test_setkey_as_array_rejects_object() {
+    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "List",
+            "-p",
+            r#"{"a": 1}"#,
+            "--as-array",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("Payload has type object, expected array")
+    );
+}
+
+#[test]
+fn test_setkey_as_number_rejects_quoted_string() {
+    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Port",
+            "-p",
+            r#""8080""#,
+            "--as-number",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("Payload has type string, expected number")
+    );
+}
+
+#[test]
+fn test_watchall_reports_changes_between_polls() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("kept", kv_num(1)).unwrap();
+    kvs.set_value("changed", kv_str("old")).unwrap();
+    kvs.set_value("removed", kv_bool(true)).unwrap();
+    let polls = Arc::new(Mutex::new(Vec::new()));
+    // Only the first wait changes the store, so the second poll reports nothing
+    let store = Arc::clone(&kvs);
+    let waits = Arc::clone(&polls);
+    let clock = move |duration: Duration| {
+        let mut waits = waits.lock().unwrap();
+        if waits.is_empty() {
+            store.set_value("changed", kv_str("new")).unwrap();
+            store.remove_key("removed").unwrap();
+            store.set_value("added", kv_arr([kv_num(2)])).unwrap();
+        }
+        waits.push(duration);
+    };
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::from_arc(kvs)
+        .with_sink(sink.clone())
+        .with_clock(Arc::new(clock));
+
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "watchall", "--interval", "250", "--count", "2"])
+        .unwrap();
+    let OperationOutcome::Strings(changes) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        changes,
+        vec![
+            "added added: [2]".to_string(),
+            "changed changed: \"old\" -> \"new\"".to_string(),
+            "removed removed".to_string(),
+        ]
+    );
+    assert_eq!(*sink.lock().unwrap(), changes);
+    assert_eq!(*polls.lock().unwrap(), vec![Duration::from_millis(250); 2]);
+}
+
+#[test]
+fn test_sizereport_totals_per_type() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(["a", "b", "c", "d"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+    mock.expect_get_value_raw()
+        .times(4)
+        .returning(|key| match key {
+            "a" => Ok(kv_str("hello")),
+            "b" => Ok(kv_str("abc")),
+            "c" => Ok(kv_num(1)),
+            _ => Ok(kv_arr([kv_num(2), kv_str("xy")])),
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper.execute_operation(vec!["-o", "sizereport"]).unwrap();
+    let OperationOutcome::SizeReport {
+        by_type,
+        total_bytes,
+    } = outcome
+    else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        by_type,
+        vec![("number", (1, 8)), ("string", (2, 8)), ("array", (1, 10))]
+    );
+    assert_eq!(total_bytes, 26);
+}
+
+#[test]
+fn test_allowlist_rejects_other_operations_up_front() {
+    // The mock has no expectations, so any store access would fail the test
+    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()))
+        .with_allowed_operations(&["getkey", "listkeys"]);
+    let err = wrapper
+        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "1", "--backup"])
+        .unwrap_err();
+    assert_eq!(err.code, ToolErrorCode::OperationNotPermitted);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("Operation 'setkey' is not permitted by this deployment")
+    );
+    assert!(render_error_json(&err)
+        .unwrap()
+        .contains(r#""error":"OperationNotPermitted""#));
+}
+
+#[test]
+fn test_denylist_rejects_listed_operation() {
+    let wrapper =
+        KvsToolWrapper::new(Box::new(MockKvsMock::new())).with_denied_operations(&["reset"]);
+    let err = wrapper.execute_operation(vec!["-o", "reset"]).unwrap_err();
+    assert_eq!(err.code, ToolErrorCode::OperationNotPermitted);
+}
+
+#[test]
+fn test_finish_flushes_batched_writes() {
+    let written = Arc::new(AtomicUsize::new(0));
+    let mut mock = MockKvsMock::new();
+    let counter = written.clone();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "First" || key == "Second")
+        .times(2)
+        .returning(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(BatchedKvs::new(Box::new(mock))));
+    for key in ["First", "Second"] {
+        assert!(wrapper
+            .execute_operation(vec!["-o", "setkey", "-k", key, "-p", "1"])
+            .is_ok());
+    }
+    assert_eq!(written.load(Ordering::SeqCst), 0);
+
+    wrapper.finish().unwrap();
+    assert_eq!(written.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_typedrift_reports_changed_value_type() {
+    let first = write_temp_file(
+        "kvs_tool_typedrift_1.json",
+        r#"{"Port": 8080, "Name": "a", "Removed": true}"#,
+    );
+    let second = write_temp_file(
+        "kvs_tool_typedrift_2.json",
+        r#"{"Port": "8080", "Name": "b", "Added": 1}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_count().returning(|| 2);
+    mock.expect_get_kvs_filename()
+        .times(2)
+        .returning(move |id| match id {
+            1 => first.clone(),
+            _ => second.clone(),
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "typedrift", "-s", "1", "--snapshotid2", "2"])
+        .unwrap();
+    let OperationOutcome::TypeDrift(drifted) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(drifted, vec![("Port".to_string(), "number", "string")]);
+}
+
+#[test]
+fn test_setkey_base64_round_trips_through_getkey_decode() {
+    let stored = Arc::new(Mutex::new(None));
+    let mut mock = MockKvsMock::new();
+    let written = stored.clone();
+    mock.expect_set_value()
+        .withf(|key: &str, _: &KvsValue| key == "Blob")
+        .times(1)
+        .returning(move |_, value| {
+            *written.lock().unwrap() = Some(value);
+            Ok(())
+        });
+    mock.expect_key_exists().returning(|_| Ok(true));
+    let read = stored.clone();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Blob")
+        .times(1)
+        .returning(move |_| Ok(read.lock().unwrap().clone().unwrap()));
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_sink(sink.clone());
+    let payload = "{not json}\u{1}\u{7f}é";
+    wrapper
+        .execute_operation(vec![
+            "-o", "setkey", "-k", "Blob", "-p", payload, "--base64",
+        ])
+        .unwrap();
+    assert!(matches!(
+        stored.lock().unwrap().as_ref(),
+        Some(KvsValue::String(s)) if *s == base64_encode(payload.as_bytes())
+    ));
+
+    wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Blob", "--decode"])
+        .unwrap();
+    assert_eq!(*sink.lock().unwrap(), vec![payload.to_string()]);
+}
+
+#[test]
+fn test_getkey_decode_rejects_non_string() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().returning(|_| Ok(true));
+    mock.expect_get_value_raw().returning(|_| Ok(kv_num(3)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Count", "--decode"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+}
+
+#[test]
+fn test_base64_matches_known_encodings() {
+    for (plain, encoded) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+    ] {
+        assert_eq!(base64_encode(plain.as_bytes()), encoded);
+        assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+    }
+    assert!(base64_decode("Zm9v!===").is_none());
+}
+
+#[test]
+fn test_transaction_blocks_reset_until_rolled_back() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_value().times(0);
+    mock.expect_reset().times(1).returning(|| Ok(()));
+
+    let mut transaction = Transaction::begin(&mock);
+    transaction.set_value("MyKey", kv_num(1)).unwrap();
+    assert!(kvs_eq(
+        &transaction.get_value_raw("MyKey").unwrap(),
+        &kv_num(1)
+    ));
+
+    let err = transaction.reset().unwrap_err();
+    assert_eq!(err.code, ErrorCode::ResourceBusy);
+    assert!(err.detail.unwrap().contains("commit or roll back first"));
+    assert_eq!(
+        transaction.snapshot_restore(1).unwrap_err().code,
+        ErrorCode::ResourceBusy
+    );
+
+    transaction.rollback();
+    transaction.reset().unwrap();
+}
+
+#[test]
+fn test_transaction_commit_applies_buffered_writes() {
+    let kvs = MemKvs::default();
+    kvs.set_value("Old", kv_num(1)).unwrap();
+
+    let mut transaction = Transaction::begin(&kvs);
+    transaction.set_value("New", kv_num(2)).unwrap();
+    transaction.remove_key("Old").unwrap();
+    assert_eq!(
+        transaction.get_value_raw("Old").unwrap_err(),
+        ErrorCode::KeyNotFound
+    );
+    assert!(kvs.key_exists("Old").unwrap());
+
+    transaction.commit().unwrap();
+    assert!(!kvs.key_exists("Old").unwrap());
+    assert!(kvs_eq(&kvs.get_value_raw("New").unwrap(), &kv_num(2)));
+}
+
+#[test]
+fn test_reset_refused_while_wrapper_transaction_open() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("MyKey", kv_num(1)).unwrap();
+    let wrapper = KvsToolWrapper::from_arc(kvs.clone());
+
+    let mut transaction = wrapper.begin_transaction();
+    transaction.set_value("Other", kv_num(2)).unwrap();
+    let err = wrapper.execute_operation(vec!["-o", "reset"]).unwrap_err();
+    assert_eq!(err.code, ErrorCode::ResourceBusy);
+    assert!(err.detail.unwrap().contains("commit or roll back first"));
+    assert_eq!(
+        wrapper
+            .execute_operation(vec!["-o", "snapshotrestore", "-s", "1"])
+            .unwrap_err()
+            .code,
+        ErrorCode::ResourceBusy
+    );
+    assert!(kvs.key_exists("MyKey").unwrap());
+
+    transaction.rollback();
+    wrapper.execute_operation(vec!["-o", "reset"]).unwrap();
+    assert!(!kvs.key_exists("MyKey").unwrap());
+}
+
+#[test]
+fn test_driftfromdefault_shows_changed_nested_field() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(["db", "name", "extra"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+    mock.expect_get_default_value().returning(|key| match key {
+        "db" => Ok(kv_obj([("host", kv_str("a")), ("port", kv_num(5432))])),
+        "name" => Ok(kv_str("demo")),
+        _ => Err(ErrorCode::KeyNotFound),
+    });
+    mock.expect_get_value_raw().returning(|key| match key {
+        "db" => Ok(kv_obj([("host", kv_str("a")), ("port", kv_num(6543))])),
+        _ => Ok(kv_str("demo")),
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "driftfromdefault"])
+        .unwrap();
+    let OperationOutcome::Diffs(diffs) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        diffs,
+        vec![("db".to_string(), "- port: 5432\n+ port: 6543".to_string())]
+    );
+}
+
+#[test]
+fn test_kvs_eq_within_epsilon() {
+    let sum = kv_num(0.1 + 0.2);
+    assert!(!kvs_eq(&sum, &kv_num(0.3)));
+    assert!(kvs_eq_within(&sum, &kv_num(0.3), 1e-9));
+    assert!(kvs_eq_within(
+        &kv_arr([sum.clone()]),
+        &kv_arr([kv_num(0.3)]),
+        1e-9
+    ));
+    assert!(!kvs_eq_within(&sum, &kv_num(0.31), 1e-9));
+}
+
+#[test]
+fn test_assert_compares_numbers_within_epsilon() {
+    let file = write_temp_file("kvs_tool_assert_epsilon.json", r#"{"ratio": 0.3}"#);
+    let store = || {
+        let mut mock = MockKvsMock::new();
+        mock.expect_get_all_keys()
+            .returning(|| Ok(vec!["ratio".to_string()]));
+        mock.expect_get_value_raw()
+            .returning(|_| Ok(kv_num(0.1 + 0.2)));
+        Box::new(mock)
+    };
+
+    let exact = KvsToolWrapper::new(store());
+    let err = exact
+        .execute_operation(vec!["-o", "assert", "-f", &file])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+
+    let tolerant = KvsToolWrapper::new(store()).with_epsilon(1e-9);
+    assert!(tolerant
+        .execute_operation(vec!["-o", "assert", "-f", &file])
+        .is_ok());
+
+    // --epsilon overrides the wrapper's tolerance for a single call
+    assert!(KvsToolWrapper::new(store())
+        .execute_operation(vec!["-o", "assert", "-f", &file, "--epsilon", "1e-9"])
+        .is_ok());
+    let err = tolerant
+        .execute_operation(vec!["-o", "assert", "-f", &file, "--epsilon", "0"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    let err = exact
+        .execute_operation(vec!["-o", "assert", "-f", &file, "--epsilon", "-1"])
+        .unwrap_err();
+    assert!(err.detail.unwrap().contains("--epsilon"));
+}
+
+#[test]
+fn test_capabilities_reflect_backend() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_supports_snapshots().times(1).returning(|| true);
+    mock.expect_supports_raw_reads()
+        .times(1)
+        .returning(|| false);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "capabilities"])
+        .unwrap();
+    let OperationOutcome::Capabilities(report) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        report,
+        vec![
+            ("snapshots", true),
+            ("raw_reads", false),
+            ("concurrent_reads", false),
+            ("streaming", true),
+        ]
+    );
+}
+
+#[test]
+fn test_setdefault_writes_default_not_value() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_set_default_value()
+        .withf(|key: &str, value: &KvsValue| key == "MyKey" && kvs_eq(value, &kv_num(42)))
+        .times(1)
+        .returning(|_, _| Ok(()));
+    mock.expect_set_value().times(0);
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    assert!(wrapper
+        .execute_operation(vec!["-o", "setdefault", "-k", "MyKey", "-p", "42"])
+        .is_ok());
+}
+
+#[test]
+fn test_fail_on_empty_turns_empty_store_into_error() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().returning(|| Ok(Vec::new()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    for operation in ["listkeys", "dumpall", "export"] {
+        assert!(wrapper.execute_operation(vec!["-o", operation]).is_ok());
+        let err = wrapper
+            .execute_operation(vec!["-o", operation, "--fail-on-empty"])
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::KeyNotFound, "{}", operation);
+    }
+}
+
+// Field names and values of a span or event, as recorded by RecordingSubscriber
+#[cfg(feature = "tracing")]
+type RecordedFields = HashMap<String, String>;
+
+// Records the fields of every new span and event, for the tracing tests
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct RecordingSubscriber {
+    spans: Arc<Mutex<Vec<(&'static str, RecordedFields)>>>,
+    events: Arc<Mutex<Vec<RecordedFields>>>,
+    next_id: Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(feature = "tracing")]
+struct FieldRecorder<'a>(&'a mut RecordedFields);
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for FieldRecorder<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for RecordingSubscriber {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut fields = HashMap::new();
+        span.record(&mut FieldRecorder(&mut fields));
+        self.spans
+            .lock()
+            .unwrap()
+            .push((span.metadata().name(), fields));
+        tracing::span::Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut FieldRecorder(&mut fields));
+        self.events.lock().unwrap().push(fields);
+    }
+    fn enter(&self, _span: &tracing::span::Id) {}
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_span_for_getkey_carries_key() {
+    let wrapper = KvsToolWrapper::new(Box::new(TracingKvs::new(Box::new(getkey_mock(
+        kv_str("Hello"),
+        "Hello",
+    )))));
+    let subscriber = RecordingSubscriber::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        wrapper
+            .execute_operation(vec!["-o", "getkey", "-k", "MyKey"])
+            .unwrap();
+    });
+
+    let spans = subscriber.spans.lock().unwrap();
+    assert_eq!(spans.len(), 1);
+    let (name, fields) = &spans[0];
+    assert_eq!(*name, "kvs_operation");
+    assert_eq!(fields.get("operation").map(String::as_str), Some("getkey"));
+    assert_eq!(fields.get("key").map(String::as_str), Some("MyKey"));
+    let events = subscriber.events.lock().unwrap();
+    assert!(events
+        .iter()
+        .any(|event| event.get("method").map(String::as_str) == Some("key_exists")));
+}
+
+fn grep_mock() -> MockKvsMock {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_all_keys().returning(|| {
+        Ok(["greeting", "motto", "port"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+    mock.expect_get_value_string().returning(|key| match key {
+        "greeting" => Ok("\"say foo\"".to_string()),
+        "motto" => Ok("{\"word\":\"foobar\"}".to_string()),
+        _ => Ok("8080".to_string()),
+    });
+    mock
+}
+
+#[test]
+fn test_grep_returns_keys_whose_value_contains_text() {
+    let wrapper = KvsToolWrapper::new(Box::new(grep_mock()));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "grep", "--contains", "foo"])
+        .unwrap();
+    let OperationOutcome::Keys { names, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(names, vec!["greeting".to_string(), "motto".to_string()]);
+}
+
+#[test]
+fn test_grep_regex() {
+    let wrapper = KvsToolWrapper::new(Box::new(grep_mock()));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "grep", "--regex", "^[0-9]+$"])
+        .unwrap();
+    let OperationOutcome::Keys { names, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(names, vec!["port".to_string()]);
+}
+
+#[test]
+fn test_output_json_writes_failure_as_json() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().returning(|_| Ok(false));
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_sink(sink.clone());
+    let err = wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Missing", "--output", "json"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::KeyNotFound);
+
+    let lines = sink.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    let json: JsonValue = lines[0].parse().unwrap();
+    let JsonValue::Object(fields) = json else {
+        panic!("Expected an object, got {}", lines[0]);
+    };
+    assert!(matches!(fields.get("error"), Some(JsonValue::String(code)) if code == "KeyNotFound"));
+    assert!(
+        matches!(fields.get("detail"), Some(JsonValue::String(detail)) if detail == "Key 'Missing' not found")
+    );
+}
+
+#[test]
+fn test_seeddefaults_writes_only_defaulted_keys() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_default_keys()
+        .times(1)
+        .returning(|| Ok(vec!["AtDefault".to_string(), "Modified".to_string()]));
+    mock.expect_is_value_default()
+        .returning(|key| Ok(key == "AtDefault"));
+    mock.expect_get_default_value()
+        .withf(|key: &str| key == "AtDefault")
+        .times(1)
+        .returning(|_| Ok(kv_num(7)));
+    mock.expect_set_value()
+        .withf(|key: &str, value: &KvsValue| key == "AtDefault" && kvs_eq(value, &kv_num(7)))
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "seeddefaults"])
+        .unwrap();
+    let OperationOutcome::Keys { names, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(names, vec!["AtDefault".to_string()]);
+}
+
+#[test]
+fn test_preserve_order_survives_set_get_round_trip() {
+    let stored = Arc::new(Mutex::new(None));
+    let mut mock = MockKvsMock::new();
+    let written = stored.clone();
+    mock.expect_set_value().times(1).returning(move |_, value| {
+        *written.lock().unwrap() = Some(value);
+        Ok(())
+    });
+    mock.expect_key_exists().returning(|_| Ok(true));
+    mock.expect_is_value_default().returning(|_| Ok(false));
+    mock.expect_get_default_value()
+        .returning(|_| Ok(KvsValue::Null));
+    mock.expect_get_value_string()
+        .returning(|_| Ok("unordered".to_string()));
+    let read = stored.clone();
+    mock.expect_get_value_raw()
+        .returning(move |_| Ok(read.lock().unwrap().clone().unwrap()));
+
+    let payload = r#"{"zeta":1,"alpha":{"b":true,"a":"x\"y"},"mid":[{"y":null,"x":2}]}"#;
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    wrapper
+        .execute_operation(vec![
+            "-o",
+            "setkey",
+            "-k",
+            "Config",
+            "-p",
+            payload,
+            "--preserve-order",
+        ])
+        .unwrap();
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "getkey", "-k", "Config", "--preserve-order"])
+        .unwrap();
+    let OperationOutcome::Rendered(rendered) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(rendered, payload);
+}
+
+#[test]
+fn test_shared_wrapper_reset_during_gets() {
+    let defaults = HashMap::from([("key".to_string(), kv_num(0))]);
+    let kvs = Arc::new(MemKvs::with_defaults(defaults));
+    kvs.set_value("key", kv_num(1)).unwrap();
+    let wrapper = KvsToolWrapper::shared(Arc::clone(&kvs));
+
+    let getters: Vec<_> = (0..4)
+        .map(|_| {
+            let wrapper = Arc::clone(&wrapper);
+            std::thread::spawn(move || {
+                (0..100)
+                    .map(
+                        |_| match wrapper.execute_operation(vec!["-o", "getkey", "-k", "key"]) {
+                            Ok(OperationOutcome::Rendered(value)) => value,
+                            other => panic!("Unexpected result: {:?}", other),
+                        },
+                    )
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let resetter = {
+        let wrapper = Arc::clone(&wrapper);
+        std::thread::spawn(move || wrapper.execute_operation(vec!["-o", "reset"]).unwrap())
+    };
+
+    resetter.join().unwrap();
+    for getter in getters {
+        let seen = getter.join().unwrap();
+        // Each getter sees the set value until the reset, then only the default
+        let switch = seen
+            .iter()
+            .position(|value| value == "0")
+            .unwrap_or(seen.len());
+        assert!(seen[..switch].iter().all(|value| value == "1"));
+        assert!(seen[switch..].iter().all(|value| value == "0"));
+    }
+    assert_eq!(kvs.get_value_string("key").unwrap(), "0");
+}
+
+#[test]
+fn test_getraw_writes_string_without_quotes() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "Motd")
+        .times(1)
+        .returning(|_| Ok(kv_str("say \"hi\"\n")));
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_sink(sink.clone());
+    wrapper
+        .execute_operation(vec!["-o", "getraw", "-k", "Motd"])
+        .unwrap();
+    assert_eq!(*sink.lock().unwrap(), vec!["say \"hi\"\n".to_string()]);
+}
+
+#[test]
+fn test_getraw_rejects_non_string() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw().returning(|_| Ok(kv_num(1)));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "getraw", "-k", "Count"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+    assert!(err.detail.unwrap().ends_with("use getkey instead"));
 }
 
-// Integration tests for transitions
 #[test]
-fn test_getkey_non_existent() {
+fn test_default_operation_runs_without_arguments() {
     let mut mock = MockKvsMock::new();
-    mock.expect_key_exists()
-        .withf(|key: &str| key == "MyKey")
+    mock.expect_get_all_keys()
         .times(1)
-        .returning(|_| Ok(false));
+        .returning(|| Ok(vec!["MyKey".to_string()]));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock)).with_default_operation("listkeys");
+    let outcome = wrapper.execute_operation(Vec::new()).unwrap();
+    let OperationOutcome::Keys { names, .. } = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(names, vec!["MyKey".to_string()]);
+}
+
+#[test]
+fn test_minpatch_replaces_single_nested_field() {
+    let file = write_temp_file(
+        "kvs_tool_minpatch.json",
+        r#"{"name": "demo", "db": {"host": "a", "port": 6543}, "ports": [80, 443]}"#,
+    );
+    let mut mock = MockKvsMock::new();
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "config")
+        .times(1)
+        .returning(|_| {
+            Ok(kv_obj([
+                ("name", kv_str("demo")),
+                (
+                    "db",
+                    kv_obj([("host", kv_str("a")), ("port", kv_num(5432))]),
+                ),
+                ("ports", kv_arr([kv_num(80), kv_num(443)])),
+            ]))
+        });
+    mock.expect_set_value().times(0);
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    let result = wrapper.execute_operation(vec!["-o", "getkey", "-k", "MyKey"]);
-    assert!(result.is_err());
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "minpatch", "-k", "config", "-f", &file])
+        .unwrap();
+    let OperationOutcome::Rendered(patch) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        patch,
+        r#"[{"op":"replace","path":"/db/port","value":6543}]"#
+    );
 }
 
 #[test]
-fn test_setkey_getkey_transition() {
+fn test_minpatch_ops_apply_back_to_desired() {
+    let old = kv_obj([
+        ("a/b", kv_num(1)),
+        ("gone", kv_bool(true)),
+        ("list", kv_arr([kv_num(1), kv_num(2), kv_num(3)])),
+    ]);
+    let new = kv_obj([
+        ("a/b", kv_str("x")),
+        ("added", kv_null()),
+        ("list", kv_arr([kv_num(1)])),
+    ]);
+    let mut ops = Vec::new();
+    diff_patch_ops("", &old, &new, &mut ops).unwrap();
+    let mut doc = old.clone();
+    for op in &ops {
+        apply_patch_op(&mut doc, &op.parse::<JsonValue>().unwrap()).unwrap();
+    }
+    assert!(kvs_eq(&doc, &new));
+}
+
+#[test]
+fn test_import_latin1_file() {
+    let path = std::env::temp_dir().join("kvs_tool_import_latin1.json");
+    fs::write(&path, b"{\"name\": \"Caf\xe9\"}").unwrap();
+    let file = path.to_string_lossy().into_owned();
     let mut mock = MockKvsMock::new();
     mock.expect_set_value()
         .withf(|key: &str, value: &KvsValue| {
-            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+            key == "name" && matches!(value, KvsValue::String(s) if s == "Café")
         })
         .times(1)
         .returning(|_, _| Ok(()));
-    mock.expect_key_exists()
-        .withf(|key: &str| key == "MyKey")
-        .times(1)
-        .returning(|_| Ok(true));
-    mock.expect_is_value_default()
-        .withf(|key: &str| key == "MyKey")
-        .times(1)
-        .returning(|_| Ok(false));
-    mock.expect_get_default_value()
-        .withf(|key: &str| key == "MyKey")
-        .times(1)
-        .returning(|_| Ok(KvsValue::String("Default".to_string())));
-    mock.expect_get_value_string()
-        .withf(|key: &str| key == "MyKey")
-        .times(1)
-        .returning(|_| Ok("Hello World".to_string()));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
-        .is_ok());
-    assert!(wrapper
-        .execute_operation(vec!["-o", "getkey", "-k", "MyKey"])
-        .is_ok());
+    let err = wrapper
+        .execute_operation(vec!["-o", "import", "-f", &file])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+    assert!(err.detail.unwrap().contains("invalid UTF-8 at byte 13"));
+
+    let outcome = wrapper.execute_operation(vec![
+        "-o",
+        "import",
+        "-f",
+        &file,
+        "--input-encoding",
+        "latin1",
+    ]);
+    assert!(matches!(outcome, Ok(OperationOutcome::Imported(1))));
 }
 
 #[test]
-fn test_setkey_json_transition() {
+fn test_casecollisions_groups_keys_differing_in_case() {
     let mut mock = MockKvsMock::new();
-    mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "MyKey"
-                && matches!(
-                    value,
-                    KvsValue::Object(hm) if hm.get("sub-number").map(|v| matches!(v, KvsValue::Number(n) if *n == 789.0)).unwrap_or(false)
-                )
-        })
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(["config", "Unique", "Config"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect())
+    });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "casecollisions"])
+        .unwrap();
+    let OperationOutcome::Groups(groups) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(
+        groups,
+        vec![vec!["Config".to_string(), "config".to_string()]]
+    );
+}
+
+#[test]
+fn test_atomic_batch_rolls_back_on_failure() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("Existing", kv_num(0)).unwrap();
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+
+    let err = wrapper
+        .execute_batch(&[
+            vec!["--atomic"],
+            vec!["-o", "setkey", "-k", "Existing", "-p", "1"],
+            vec!["-o", "setkey", "-k", "Created", "-p", "2"],
+            vec!["-o", "setkey", "-k", "Bad", "-p", "text", "--as-number"],
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::ConversionFailed);
+    assert!(kvs_eq(&kvs.get_value_raw("Existing").unwrap(), &kv_num(0)));
+    assert!(!kvs.key_exists("Created").unwrap());
+
+    let outcomes = wrapper
+        .execute_batch(&[
+            vec!["--atomic"],
+            vec!["-o", "setkey", "-k", "Existing", "-p", "1"],
+            vec!["-o", "listkeys"],
+        ])
+        .unwrap();
+    assert_eq!(outcomes.len(), 2);
+    assert!(kvs_eq(&kvs.get_value_raw("Existing").unwrap(), &kv_num(1)));
+}
+
+#[test]
+fn test_sizestats_percentiles() {
+    let mut mock = MockKvsMock::new();
+    // Strings of 1 to 20 bytes, listed out of order
+    mock.expect_get_all_keys()
         .times(1)
-        .returning(|_, _| Ok(()));
+        .returning(|| Ok((1..=20).rev().map(|len| len.to_string()).collect()));
+    mock.expect_get_value_raw()
+        .times(20)
+        .returning(|key| Ok(kv_str(&"x".repeat(key.parse().unwrap()))));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
+    let outcome = wrapper.execute_operation(vec!["-o", "sizestats"]).unwrap();
+    let OperationOutcome::SizeStats {
+        count,
+        min,
+        max,
+        mean,
+        p50,
+        p95,
+    } = outcome
+    else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!((count, min, max, p50, p95), (20, 1, 20, 10, 19));
+    assert_eq!(mean, 10.5);
+}
+
+#[test]
+fn test_conflicting_options_rejected_up_front() {
+    // No expectations: the store must not be touched
+    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()));
+    let err = wrapper
+        .execute_operation(vec![
+            "-o", "getkey", "-k", "Blob", "--decode", "--output", "json",
+        ])
+        .unwrap_err();
+    assert_eq!(err.code, ToolErrorCode::ConflictingOptions);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("--output json conflicts with --decode: --decode writes raw bytes, which are not a JSON document")
+    );
+
+    let err = wrapper
+        .execute_operation(vec!["-o", "export", "--format", "yaml", "--output", "json"])
+        .unwrap_err();
+    assert!(err
+        .detail
+        .unwrap()
+        .starts_with("--output json conflicts with --format yaml"));
+
+    let err = wrapper
         .execute_operation(vec![
             "-o",
             "setkey",
             "-k",
-            "MyKey",
+            "K",
             "-p",
-            r#"{"sub-number":789,"sub-string":"Third"}"#
+            "{}",
+            "--base64",
+            "--preserve-order",
         ])
-        .is_ok());
+        .unwrap_err();
+    assert!(err
+        .detail
+        .unwrap()
+        .starts_with("--base64 conflicts with --preserve-order"));
 }
 
 #[test]
-fn test_setkey_removekey_transition() {
+fn test_conflicting_options_ignore_option_values() {
+    // A payload that reads like a flag is a value, not --decode
     let mut mock = MockKvsMock::new();
     mock.expect_set_value()
         .withf(|key: &str, value: &KvsValue| {
-            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+            key == "K" && matches!(value, KvsValue::String(s) if s == "--decode")
         })
         .times(1)
         .returning(|_, _| Ok(()));
-    mock.expect_remove_key()
-        .withf(|key: &str| key == "MyKey")
-        .times(1)
-        .returning(|_| Ok(()));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
     assert!(wrapper
-        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
-        .is_ok());
-    assert!(wrapper
-        .execute_operation(vec!["-o", "removekey", "-k", "MyKey"])
+        .execute_operation(vec![
+            "-o", "setkey", "-k", "K", "-p", "--decode", "--output", "json",
+        ])
         .is_ok());
 }
 
-#[test]
-fn test_setkey_listkeys_transition() {
-    let mut mock = MockKvsMock::new();
-    mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "Key1" && matches!(value, KvsValue::String(s) if s == "Value1")
-        })
-        .times(1)
-        .returning(|_, _| Ok(()));
-    mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "Key2" && matches!(value, KvsValue::String(s) if s == "Value2")
-        })
+fn reconcile_target_mock() -> MockKvsMock {
+    let mut target = MockKvsMock::new();
+    target
+        .expect_get_all_keys()
         .times(1)
-        .returning(|_, _| Ok(()));
-    mock.expect_get_all_keys()
+        .returning(|| Ok(vec!["B".to_string(), "C".to_string()]));
+    target
+        .expect_get_value_raw()
+        .withf(|key: &str| key == "B")
         .times(1)
-        .returning(|| Ok(vec!["Key1".to_string(), "Key2".to_string()]));
+        .returning(|_| Ok(KvsValue::String("stale".to_string())));
+    target
+}
 
-    let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "setkey", "-k", "Key1", "-p", "Value1"])
-        .is_ok());
-    assert!(wrapper
-        .execute_operation(vec!["-o", "setkey", "-k", "Key2", "-p", "Value2"])
-        .is_ok());
-    assert!(wrapper.execute_operation(vec!["-o", "listkeys"]).is_ok());
+#[test]
+fn test_reconcile_reports_missing_and_differing_keys() {
+    let wrapper = KvsToolWrapper::new(Box::new(clone_source_mock()))
+        .with_instance(2, Arc::new(reconcile_target_mock()));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "reconcile", "--instance", "2"])
+        .unwrap();
+    let OperationOutcome::Plan(plan) = outcome else {
+        panic!("Expected a change plan, got {:?}", outcome);
+    };
+    assert_eq!(plan.create, vec!["A"]);
+    assert_eq!(plan.update, vec!["B"]);
+    assert_eq!(plan.delete, vec!["C"]);
 }
 
 #[test]
-fn test_setkey_reset_transition() {
-    let mut mock = MockKvsMock::new();
-    mock.expect_set_value()
+fn test_reconcile_apply_makes_instance_match() {
+    let mut target = reconcile_target_mock();
+    target
+        .expect_set_value()
         .withf(|key: &str, value: &KvsValue| {
-            key == "MyKey" && matches!(value, KvsValue::String(s) if s == "Hello World")
+            key == "A" && matches!(value, KvsValue::Number(n) if *n == 1.0)
         })
         .times(1)
         .returning(|_, _| Ok(()));
-    mock.expect_reset().times(1).returning(|| Ok(()));
+    target
+        .expect_set_value()
+        .withf(|key: &str, value: &KvsValue| {
+            key == "B" && matches!(value, KvsValue::String(s) if s == "b")
+        })
+        .times(1)
+        .returning(|_, _| Ok(()));
+    target
+        .expect_remove_key()
+        .withf(|key: &str| key == "C")
+        .times(1)
+        .returning(|_| Ok(()));
 
-    let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "setkey", "-k", "MyKey", "-p", "Hello World"])
-        .is_ok());
-    assert!(wrapper.execute_operation(vec!["-o", "reset"]).is_ok());
+    let wrapper =
+        KvsToolWrapper::new(Box::new(clone_source_mock())).with_instance(2, Arc::new(target));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "reconcile", "--instance", "2", "--apply"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Applied(_)));
 }
 
 #[test]
-fn test_snapshotcount() {
-    let mut mock = MockKvsMock::new();
-    mock.expect_snapshot_count().times(1).returning(|| 2);
+fn test_per_type_reports_follow_type_names_order() {
+    for _ in 0..5 {
+        // A fresh store per run, so each run gets its own HashMap iteration order
+        let kvs = Arc::new(MemKvs::default());
+        let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+        wrapper
+            .execute_operation(vec!["-o", "createtestdata"])
+            .unwrap();
+        let outcome = wrapper.execute_operation(vec!["-o", "sizereport"]).unwrap();
+        let OperationOutcome::SizeReport { by_type, .. } = outcome else {
+            panic!("Unexpected outcome: {:?}", outcome);
+        };
+        let names: Vec<&str> = by_type.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, TYPE_NAMES);
 
-    let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper.execute_operation(vec!["-o", "snapshotcount"]).is_ok());
+        let outcome = wrapper
+            .execute_operation(vec!["-o", "createtestdata", "--count", "60"])
+            .unwrap();
+        let OperationOutcome::TypeCounts(type_counts) = outcome else {
+            panic!("Unexpected outcome: {:?}", outcome);
+        };
+        let names: Vec<&str> = type_counts.iter().map(|(name, _)| *name).collect();
+        let expected: Vec<&str> = TYPE_NAMES
+            .into_iter()
+            .filter(|name| names.contains(name))
+            .collect();
+        assert_eq!(names, expected);
+    }
 }
 
 #[test]
-fn test_snapshotmaxcount() {
-    let wrapper = KvsToolWrapper::new(Box::new(MockKvsMock::new()));
-    assert!(wrapper.execute_operation(vec!["-o", "snapshotmaxcount"]).is_ok());
+fn test_selftest_round_trip_leaves_store_unchanged() {
+    let kvs = Arc::new(MemKvs::default());
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+    let outcome = wrapper.execute_operation(vec!["-o", "selftest"]).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
+    assert!(kvs.get_all_keys().unwrap().is_empty());
 }
 
 #[test]
-fn test_snapshotrestore() {
+fn test_selftest_probe_only_never_writes() {
+    // No set_value or remove_key expectations, so any write fails the test
     let mut mock = MockKvsMock::new();
-    mock.expect_snapshot_restore()
-        .withf(|id: &u32| *id == 1)
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "known")
         .times(1)
-        .returning(|_| Ok(()));
+        .returning(|_| Ok(KvsValue::Number(1.0)));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "snapshotrestore", "-s", "1"])
-        .is_ok());
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "selftest", "--probe-only", "-k", "known"])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Done));
 }
 
 #[test]
-fn test_getkvsfilename() {
-    let mut mock = MockKvsMock::new();
-    mock.expect_get_kvs_filename()
-        .withf(|id: &u32| *id == 1)
-        .times(1)
-        .returning(|id| format!("kvs_0_{}.json", id));
+fn test_get_typed_converts_into_rust_types() {
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("Number", kv_num(42)).unwrap();
+    kvs.set_value("Name", kv_str("kvs")).unwrap();
+    let wrapper = KvsToolWrapper::from_arc(kvs);
 
-    let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "getkvsfilename", "-s", "1"])
-        .is_ok());
+    assert_eq!(wrapper.get_typed::<f64>("Number"), Ok(42.0));
+    assert_eq!(wrapper.get_typed::<String>("Name"), Ok("kvs".to_string()));
+    assert_eq!(
+        wrapper.get_typed::<f64>("Name"),
+        Err(ErrorCode::ConversionFailed)
+    );
 }
 
 #[test]
-fn test_gethashfilename() {
+fn test_expect_reports_only_listed_mismatches() {
+    let file = write_temp_file("kvs_tool_expect.json", r#"{"same":"x","changed":true}"#);
     let mut mock = MockKvsMock::new();
-    mock.expect_get_hash_filename()
-        .withf(|id: &u32| *id == 1)
+    mock.expect_get_all_keys().times(1).returning(|| {
+        Ok(vec![
+            "same".to_string(),
+            "changed".to_string(),
+            "extra".to_string(),
+        ])
+    });
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "same")
         .times(1)
-        .returning(|id| format!("kvs_0_{}.hash", id));
+        .returning(|_| Ok(KvsValue::String("x".to_string())));
+    mock.expect_get_value_raw()
+        .withf(|key: &str| key == "changed")
+        .times(1)
+        .returning(|_| Ok(KvsValue::Boolean(false)));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "gethashfilename", "-s", "1"])
-        .is_ok());
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "expect", "-f", &file])
+        .unwrap();
+    let OperationOutcome::Strings(mismatches) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert_eq!(mismatches, vec!["differs 'changed'".to_string()]);
 }
 
 #[test]
-fn test_createtestdata() {
+fn test_failed_rollback_names_unrestored_keys() {
     let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .times(2)
+        .returning(|key| Ok(kv_str(key)));
+    // The first write succeeds, the second fails, and so does restoring the first
     mock.expect_set_value()
         .withf(|key: &str, value: &KvsValue| {
-            key == "number" && matches!(value, KvsValue::Number(n) if n == &123.0)
+            key == "A" && matches!(value, KvsValue::String(s) if s == "B")
         })
         .times(1)
         .returning(|_, _| Ok(()));
     mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "bool" && matches!(value, KvsValue::Boolean(b) if *b)
-        })
+        .withf(|key: &str, _: &KvsValue| key == "B")
         .times(1)
-        .returning(|_, _| Ok(()));
+        .returning(|_, _| Err(ErrorCode::PhysicalStorageFailure));
     mock.expect_set_value()
         .withf(|key: &str, value: &KvsValue| {
-            key == "string" && matches!(value, KvsValue::String(s) if s == "First")
+            key == "A" && matches!(value, KvsValue::String(s) if s == "A")
         })
         .times(1)
-        .returning(|_, _| Ok(()));
-    mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| key == "null" && matches!(value, KvsValue::Null))
-        .times(1)
-        .returning(|_, _| Ok(()));
-    mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "array"
-                && matches!(
-                    value,
-                    KvsValue::Array(vec) if vec.len() == 3
-                        && matches!(vec[0], KvsValue::Number(n) if n == 456.0)
-                        && matches!(vec[1], KvsValue::Boolean(b) if !b)
-                        && matches!(&vec[2], KvsValue::String(s) if s == "Second")
-                )
-        })
+        .returning(|_, _| Err(ErrorCode::PhysicalStorageFailure));
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let err = wrapper
+        .execute_operation(vec!["-o", "swap", "-k", "A", "--newkey", "B"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::PhysicalStorageFailure);
+    assert_eq!(
+        err.detail.as_deref(),
+        Some("PhysicalStorageFailure; rollback could not restore 'A'")
+    );
+}
+
+#[test]
+fn test_exportdiff_patch_turns_first_snapshot_into_second() {
+    let first = write_temp_file(
+        "kvs_tool_exportdiff_1.json",
+        r#"{"Port": 8080, "Name": "a", "Dropped": true}"#,
+    );
+    let second = write_temp_file(
+        "kvs_tool_exportdiff_2.json",
+        r#"{"Port": 9090, "Name": "a", "Added": [1]}"#,
+    );
+    let out = std::env::temp_dir().join("kvs_tool_exportdiff_patch.json");
+    let out = out.to_string_lossy().into_owned();
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_count().returning(|| 2);
+    let (first_file, second_file) = (first.clone(), second.clone());
+    mock.expect_get_kvs_filename()
+        .times(2)
+        .returning(move |id| match id {
+            1 => first_file.clone(),
+            _ => second_file.clone(),
+        });
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec![
+            "-o",
+            "exportdiff",
+            "-s",
+            "1",
+            "--snapshotid2",
+            "2",
+            "-f",
+            &out,
+        ])
+        .unwrap();
+    assert!(matches!(outcome, OperationOutcome::Count(3)));
+
+    // Replaying the patch on a store holding the first snapshot yields the second
+    let kvs = Arc::new(MemKvs::default());
+    for (key, value) in read_key_value_file(&first).unwrap() {
+        kvs.set_value(&key, value).unwrap();
+    }
+    let replay = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+    replay
+        .execute_operation(vec!["-o", "patch", "-f", &out])
+        .unwrap();
+    let state = KvsValue::Object(replay.read_all().unwrap());
+    let expected = KvsValue::Object(read_key_value_file(&second).unwrap());
+    assert!(kvs_eq(&state, &expected));
+}
+
+#[test]
+#[should_panic(expected = "Unexpected call to remove_key on StrictMockKvs")]
+fn test_strict_mock_fails_on_unexpected_remove_key() {
+    // Without .times() this expectation alone would accept the call
+    let mut mock = MockKvsMock::new();
+    mock.expect_remove_key().returning(|_| Ok(()));
+
+    let wrapper = KvsToolWrapper::new(Box::new(StrictMockKvs::new(mock).allow(&["key_exists"])));
+    let _ = wrapper.execute_operation(vec!["-o", "removekey", "-k", "A"]);
+}
+
+#[test]
+fn test_dumpsnapshot_pretty_prints_snapshot_file() {
+    let snapshot = write_temp_file(
+        "kvs_tool_dumpsnapshot.json",
+        r#"{"Port": 8080, "Nested": {"a": [1, "x"]}}"#,
+    );
+    // No live-state expectations, so get_all_keys or get_value_raw would fail the test
+    let mut mock = MockKvsMock::new();
+    mock.expect_snapshot_count().returning(|| 2);
+    let file = snapshot.clone();
+    mock.expect_get_kvs_filename()
+        .withf(|&id| id == 2)
         .times(1)
-        .returning(|_, _| Ok(()));
+        .returning(move |_| file.clone());
+
+    let wrapper = KvsToolWrapper::new(Box::new(mock));
+    let outcome = wrapper
+        .execute_operation(vec!["-o", "dumpsnapshot", "-s", "2"])
+        .unwrap();
+    let OperationOutcome::Rendered(text) = outcome else {
+        panic!("Unexpected outcome: {:?}", outcome);
+    };
+    assert!(text.contains('\n'));
+    let dumped = convert_json_to_kvs(&text.parse::<JsonValue>().unwrap());
+    let expected = KvsValue::Object(read_key_value_file(&snapshot).unwrap());
+    assert!(kvs_eq(&dumped, &expected));
+}
+
+#[test]
+fn test_import_since_file_does_not_record_no_clobber_skips() {
+    let hash_file = std::env::temp_dir().join("kvs_tool_import_since_no_clobber.hashes");
+    let _ = fs::remove_file(&hash_file);
+    let hash_file = hash_file.to_string_lossy().into_owned();
+    let file = write_temp_file(
+        "kvs_tool_import_since_no_clobber.json",
+        r#"{"Kept": "file", "New": 1}"#,
+    );
+    let args = vec![
+        "-o",
+        "import",
+        "-f",
+        &file,
+        "--no-clobber",
+        "--since-file",
+        &hash_file,
+    ];
+    let kvs = Arc::new(MemKvs::default());
+    kvs.set_value("Kept", kv_str("local")).unwrap();
+    let wrapper = KvsToolWrapper::from_arc(Arc::clone(&kvs));
+
+    let outcome = wrapper.execute_operation(args.clone()).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(1)));
+    assert!(kvs_eq(
+        &kvs.get_value_raw("Kept").unwrap(),
+        &kv_str("local")
+    ));
+
+    // Once the skipped key is gone the next run restores it, while "New" stays unchanged
+    kvs.remove_key("Kept").unwrap();
+    let outcome = wrapper.execute_operation(args).unwrap();
+    assert!(matches!(outcome, OperationOutcome::Imported(1)));
+    assert!(kvs_eq(&kvs.get_value_raw("Kept").unwrap(), &kv_str("file")));
+}
+
+#[test]
+fn test_snapshot_reads_reject_live_id_zero() {
+    for op in ["changedsince", "dumpsnapshot"] {
+        // Rejected before any snapshot file is looked up
+        let mut mock = MockKvsMock::new();
+        mock.expect_snapshot_count().returning(|| 2);
+        let wrapper = KvsToolWrapper::new(Box::new(mock));
+        let err = wrapper
+            .execute_operation(vec!["-o", op, "-s", "0"])
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidSnapshotId);
+    }
+}
+
+#[test]
+fn test_swap_failed_first_write_writes_nothing_back() {
+    let mut mock = MockKvsMock::new();
+    mock.expect_key_exists().times(2).returning(|_| Ok(true));
+    mock.expect_get_value_raw()
+        .times(2)
+        .returning(|key| Ok(kv_str(key)));
+    // The only write expected; restoring "A" afterwards would fail the test
     mock.expect_set_value()
-        .withf(|key: &str, value: &KvsValue| {
-            key == "object"
-                && matches!(
-                    value,
-                    KvsValue::Object(hm) if hm.len() == 5
-                        && hm.get("sub-number").map(|v| matches!(v, KvsValue::Number(n) if n == &789.0)).unwrap_or(false)
-                        && hm.get("sub-bool").map(|v| matches!(v, KvsValue::Boolean(b) if *b)).unwrap_or(false)
-                        && hm.get("sub-string").map(|v| matches!(v, KvsValue::String(s) if s == "Third")).unwrap_or(false)
-                        && hm.get("sub-null").map(|v| matches!(v, KvsValue::Null)).unwrap_or(false)
-                        && hm.get("sub-array").map(|v| matches!(v, KvsValue::Array(vec) if vec.len() == 3
-                            && matches!(vec[0], KvsValue::Number(n) if n == 1246.0)
-                            && matches!(vec[1], KvsValue::Boolean(b) if !b)
-                            && matches!(&vec[2], KvsValue::String(s) if s == "Fourth")
-                        )).unwrap_or(false)
-                )
-        })
+        .withf(|key: &str, _: &KvsValue| key == "A")
         .times(1)
-        .returning(|_, _| Ok(()));
+        .returning(|_, _| Err(ErrorCode::PhysicalStorageFailure));
 
     let wrapper = KvsToolWrapper::new(Box::new(mock));
-    assert!(wrapper
-        .execute_operation(vec!["-o", "createtestdata"])
-        .is_ok());
-}
\ No newline at end of file
+    let err = wrapper
+        .execute_operation(vec!["-o", "swap", "-k", "A", "--newkey", "B"])
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::PhysicalStorageFailure);
+    assert_eq!(err.detail, None);
+}